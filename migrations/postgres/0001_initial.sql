@@ -0,0 +1,62 @@
+CREATE TABLE IF NOT EXISTS loc_auth (
+    id BIGSERIAL PRIMARY KEY,
+    host VARCHAR(1024),
+    api_key VARCHAR(256),  -- Only set for keys created before hashing was added
+    key_id VARCHAR(16),  -- The plaintext prefix of the key, used for lookups
+    key_salt VARCHAR(64),
+    key_hash VARCHAR(64),  -- HMAC-SHA256 of the full key, keyed with key_salt
+    rate_limit DOUBLE PRECISION,  -- Requests/sec, overrides the config default
+    rate_burst INTEGER,
+    -- Comma separated list of create, verify, qr, delete and admin
+    scopes VARCHAR(256) NOT NULL DEFAULT 'create,verify,qr,delete',
+    -- These are all unix timestamps
+    created_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+    last_used_at BIGINT,
+    expires_at BIGINT,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+-- Upgrade older tables
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS key_id VARCHAR(16);
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS key_salt VARCHAR(64);
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS key_hash VARCHAR(64);
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS rate_limit DOUBLE PRECISION;
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS rate_burst INTEGER;
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS scopes VARCHAR(256) NOT NULL
+    DEFAULT 'create,verify,qr,delete';
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS created_at BIGINT NOT NULL
+    DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT;
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS last_used_at BIGINT;
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS expires_at BIGINT;
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS revoked BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS host_idx ON loc_auth (host);
+CREATE UNIQUE INDEX IF NOT EXISTS api_key_idx ON loc_auth (api_key);
+CREATE INDEX IF NOT EXISTS key_id_idx ON loc_auth (key_id);
+
+CREATE TABLE IF NOT EXISTS secrets (
+    id BIGSERIAL PRIMARY KEY,
+    owner_id BIGINT REFERENCES loc_auth (id),  -- The api key that created this
+    ident VARCHAR(4096),  -- This is an arbitrary string identifier, unique per owner
+    token VARCHAR(512),  -- This is the actual secret token, encrypted
+    dek VARCHAR(128),  -- The data key for the token, encrypted with the master key
+    key_version INTEGER NOT NULL DEFAULT 1,  -- The version of the master key for dek
+    last_step BIGINT,  -- The last TOTP time step accepted, to prevent replays
+    failed_attempts INTEGER NOT NULL DEFAULT 0,  -- Consecutive failed verifications
+    locked_until BIGINT  -- Unix timestamp that verification is locked until
+);
+
+-- Upgrade older tables
+ALTER TABLE secrets ALTER COLUMN token TYPE VARCHAR(512);
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS owner_id BIGINT REFERENCES loc_auth (id);
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS dek VARCHAR(128);
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS key_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS last_step BIGINT;
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS locked_until BIGINT;
+
+-- Idents used to be globally unique, now they are unique per owner
+DROP INDEX IF EXISTS ident_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS owner_ident_idx ON secrets (owner_id, ident);
+CREATE INDEX IF NOT EXISTS sec_ident_idx ON secrets (ident);
+CREATE UNIQUE INDEX IF NOT EXISTS token_idx ON secrets (token);
\ No newline at end of file
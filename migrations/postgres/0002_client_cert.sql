@@ -0,0 +1,5 @@
+-- The name from a client certificate, a SAN or the subject CN, that
+-- authenticates as the key
+ALTER TABLE loc_auth ADD COLUMN IF NOT EXISTS cert_name VARCHAR(1024);
+
+CREATE UNIQUE INDEX IF NOT EXISTS cert_name_idx ON loc_auth (cert_name);
@@ -0,0 +1,5 @@
+-- The code length, time step and hmac algorithm for each secret.  The
+-- defaults match the codes every secret used before.
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS digits INTEGER NOT NULL DEFAULT 6;
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS period INTEGER NOT NULL DEFAULT 30;
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS algorithm VARCHAR(16) NOT NULL DEFAULT 'SHA1';
@@ -0,0 +1,4 @@
+-- Whether a secret is time based (totp) or counter based (hotp), and the
+-- next counter expected from an hotp token
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS type VARCHAR(8) NOT NULL DEFAULT 'totp';
+ALTER TABLE secrets ADD COLUMN IF NOT EXISTS counter BIGINT NOT NULL DEFAULT 0;
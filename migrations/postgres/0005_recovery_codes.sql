@@ -0,0 +1,11 @@
+-- Single use codes for when a user loses their authenticator.  Only salted
+-- hashes of the codes are stored, like the api keys.
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id BIGSERIAL PRIMARY KEY,
+    secret_id BIGINT NOT NULL REFERENCES secrets (id) ON DELETE CASCADE,
+    code_salt VARCHAR(64) NOT NULL,
+    code_hash VARCHAR(64) NOT NULL,  -- HMAC-SHA256 of the code, keyed with code_salt
+    used_at BIGINT  -- Unix timestamp the code was used, if it has been
+);
+
+CREATE INDEX IF NOT EXISTS recovery_secret_idx ON recovery_codes (secret_id);
@@ -0,0 +1,39 @@
+-- The SQLite version of the Postgres migration with the same number.  Keep
+-- the two in sync.
+CREATE TABLE IF NOT EXISTS loc_auth (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    host TEXT,
+    api_key TEXT,  -- Only set for keys created before hashing was added
+    key_id TEXT,  -- The plaintext prefix of the key, used for lookups
+    key_salt TEXT,
+    key_hash TEXT,  -- HMAC-SHA256 of the full key, keyed with key_salt
+    rate_limit REAL,  -- Requests/sec, overrides the config default
+    rate_burst INTEGER,
+    -- Comma separated list of create, verify, qr, delete and admin
+    scopes TEXT NOT NULL DEFAULT 'create,verify,qr,delete',
+    -- These are all unix timestamps
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+    last_used_at INTEGER,
+    expires_at INTEGER,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS host_idx ON loc_auth (host);
+CREATE UNIQUE INDEX IF NOT EXISTS api_key_idx ON loc_auth (api_key);
+CREATE INDEX IF NOT EXISTS key_id_idx ON loc_auth (key_id);
+
+CREATE TABLE IF NOT EXISTS secrets (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id INTEGER REFERENCES loc_auth (id),  -- The api key that created this
+    ident TEXT,  -- This is an arbitrary string identifier, unique per owner
+    token TEXT,  -- This is the actual secret token, encrypted
+    dek TEXT,  -- The data key for the token, encrypted with the master key
+    key_version INTEGER NOT NULL DEFAULT 1,  -- The version of the master key for dek
+    last_step INTEGER,  -- The last TOTP time step accepted, to prevent replays
+    failed_attempts INTEGER NOT NULL DEFAULT 0,  -- Consecutive failed verifications
+    locked_until INTEGER  -- Unix timestamp that verification is locked until
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS owner_ident_idx ON secrets (owner_id, ident);
+CREATE INDEX IF NOT EXISTS sec_ident_idx ON secrets (ident);
+CREATE UNIQUE INDEX IF NOT EXISTS token_idx ON secrets (token);
@@ -0,0 +1,4 @@
+-- Whether a secret is time based (totp) or counter based (hotp), and the
+-- next counter expected from an hotp token
+ALTER TABLE secrets ADD COLUMN type TEXT NOT NULL DEFAULT 'totp';
+ALTER TABLE secrets ADD COLUMN counter INTEGER NOT NULL DEFAULT 0;
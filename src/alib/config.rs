@@ -6,10 +6,13 @@ use std::path::Path;
 pub fn get_config(path: &Path) -> Ini {
     let mut conf = Ini::new();
 
-    conf.load(path).expect(&format!(
-        "Failed to load config from path: {}",
-        path.to_string_lossy()
-    ));
+    if let Err(e) = conf.load(path) {
+        panic!(
+            "Failed to load config from path: {}: {}",
+            path.to_string_lossy(),
+            e
+        );
+    }
 
     return conf;
 }
@@ -0,0 +1,516 @@
+extern crate aes_gcm;
+extern crate base64;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use configparser::ini::Ini;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const API_KEY_ID_LEN: usize = 8;
+const API_KEY_SALT_LEN: usize = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// An encrypted secret as it is stored in the database.  The `dek` is the
+/// per-row data encryption key wrapped with the master key identified by
+/// `key_version`, and the `token` is the secret itself encrypted with the
+/// (unwrapped) data key.  Both are base64 encoded `nonce || ciphertext` blobs.
+#[derive(Debug, Clone)]
+pub struct Sealed {
+    pub dek: String,
+    pub token: String,
+    pub key_version: i32,
+}
+
+/// This handles the envelope encryption for TOTP secrets at rest.  Each
+/// secret is encrypted with its own randomly generated AES-256-GCM key, and
+/// that key is then encrypted with a master key from the config.
+///
+/// There can be multiple versioned master keys, a keyring, so that the master
+/// key can be rotated.  New secrets are always sealed with the active key,
+/// while any key in the ring can be used to open an existing secret.
+#[derive(Clone)]
+pub struct Cipher {
+    keys: HashMap<i32, Key<Aes256Gcm>>,
+    active: i32,
+}
+
+impl Cipher {
+    /// Create a new cipher from the raw bytes of a single master key, which
+    /// must be exactly 32 bytes long.  This will be key version 1.
+    #[cfg(test)]
+    pub fn new(master: &[u8]) -> Result<Self> {
+        return Self::from_keys(1, &[(1, master.to_vec())]);
+    }
+
+    /// Create a new cipher from a keyring of `(version, raw key)` pairs,
+    /// sealing new secrets with the `active` version
+    pub fn from_keys(active: i32, keys: &[(i32, Vec<u8>)]) -> Result<Self> {
+        let mut ring = HashMap::new();
+
+        for (version, key) in keys.iter() {
+            if key.len() != KEY_LEN {
+                return Err(anyhow!(
+                    "Master key {} must be {} bytes, got {}",
+                    version,
+                    KEY_LEN,
+                    key.len()
+                ));
+            }
+
+            ring.insert(*version, *Key::<Aes256Gcm>::from_slice(key));
+        }
+
+        if !ring.contains_key(&active) {
+            return Err(anyhow!("The active master key {} is not defined", active));
+        }
+
+        return Ok(Self { keys: ring, active });
+    }
+
+    /// Load the master keys from the `[crypto]` section of the config.  Each
+    /// key is either a base64 encoded `key_<version>` or a `key_<version>_file`
+    /// containing the base64 encoded key, and `active_key` is the version used
+    /// for new secrets.  The older single `master_key` and `master_key_file`
+    /// options are treated as key version 1.  Setting the same version more
+    /// than once is an error.
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        let mut keys = vec![];
+        let mut names: HashMap<i32, &str> = HashMap::new();
+        let section = match conf.get_map_ref().get("crypto") {
+            Some(s) => s,
+            None => return Err(anyhow!("Missing the [crypto] config section")),
+        };
+
+        for (name, val) in section.iter() {
+            let val = match val {
+                Some(v) if !v.is_empty() => v,
+                _ => continue,
+            };
+
+            let (version, is_file) = match name.as_str() {
+                "master_key" => (1, false),
+                "master_key_file" => (1, true),
+                _ => match name.strip_prefix("key_") {
+                    Some(rest) => {
+                        let (v, is_file) = match rest.strip_suffix("_file") {
+                            Some(v) => (v, true),
+                            None => (rest, false),
+                        };
+                        let v = v
+                            .parse::<i32>()
+                            .map_err(|_| anyhow!("Invalid master key option {}", name))?;
+                        (v, is_file)
+                    }
+                    None => continue,
+                },
+            };
+
+            // Only one of the options can be used for each version, or it'd
+            // be down to the order they're read in which key wins
+            if let Some(other) = names.insert(version, name) {
+                return Err(anyhow!(
+                    "Master key version {} is set by both {} and {}",
+                    version,
+                    other,
+                    name
+                ));
+            }
+
+            let b64_key = if is_file {
+                fs::read_to_string(val)
+                    .map_err(|e| anyhow!("Failed to read master key file {}: {}", val, e))?
+            } else {
+                val.to_string()
+            };
+
+            let raw = B64
+                .decode(b64_key.trim())
+                .map_err(|e| anyhow!("Invalid base64 master key {}: {}", version, e))?;
+
+            keys.push((version, raw));
+        }
+
+        if keys.is_empty() {
+            return Err(anyhow!("No master keys are set in [crypto]"));
+        }
+
+        let active = match conf.getint("crypto", "active_key") {
+            Ok(Some(v)) => v as i32,
+            Ok(None) => keys.iter().map(|(v, _)| *v).max().unwrap(),
+            Err(e) => return Err(anyhow!("Invalid active_key: {}", e)),
+        };
+
+        return Self::from_keys(active, &keys);
+    }
+
+    /// The version of the master key used for new secrets
+    pub fn active_version(&self) -> i32 {
+        return self.active;
+    }
+
+    /// Encrypt the secret for the given owner and ident.  Both are bound to
+    /// the ciphertext as associated data so a token can't be swapped onto a
+    /// different row, even one with the same ident for another owner.
+    pub fn seal(&self, owner: Option<i64>, ident: &str, secret: &str) -> Result<Sealed> {
+        let dek = Aes256Gcm::generate_key(&mut OsRng);
+
+        let token = encrypt(&dek, secret.as_bytes(), &token_aad(owner, ident))?;
+        let dek = encrypt(self.master(self.active)?, dek.as_slice(), b"")?;
+
+        return Ok(Sealed {
+            dek,
+            token,
+            key_version: self.active,
+        });
+    }
+
+    /// Reverse of `seal()`, returning the plaintext secret
+    pub fn open(&self, owner: Option<i64>, ident: &str, sealed: &Sealed) -> Result<String> {
+        let dek = self.unwrap_dek(sealed)?;
+
+        let secret = decrypt(
+            Key::<Aes256Gcm>::from_slice(&dek),
+            &sealed.token,
+            &token_aad(owner, ident),
+        )?;
+
+        return Ok(String::from_utf8(secret)?);
+    }
+
+    /// Re-encrypt only the data key of a sealed secret under the active
+    /// master key.  The token itself is left untouched, but it has to open
+    /// for the owner and ident first so that a token moved onto another row
+    /// isn't carried over to the new key.
+    pub fn rewrap(&self, owner: Option<i64>, ident: &str, sealed: &Sealed) -> Result<Sealed> {
+        self.open(owner, ident, sealed)?;
+        let dek = self.unwrap_dek(sealed)?;
+
+        return Ok(Sealed {
+            dek: encrypt(self.master(self.active)?, &dek, b"")?,
+            token: sealed.token.clone(),
+            key_version: self.active,
+        });
+    }
+
+    fn unwrap_dek(&self, sealed: &Sealed) -> Result<Vec<u8>> {
+        let dek = decrypt(self.master(sealed.key_version)?, &sealed.dek, b"")?;
+        if dek.len() != KEY_LEN {
+            return Err(anyhow!("Invalid data key length"));
+        }
+
+        return Ok(dek);
+    }
+
+    fn master(&self, version: i32) -> Result<&Key<Aes256Gcm>> {
+        return self
+            .keys
+            .get(&version)
+            .ok_or_else(|| anyhow!("Master key version {} is not in the keyring", version));
+    }
+}
+
+/// An API key as it is stored in the database.  The `key_id` is the
+/// plaintext prefix of the key, used to look up the row, and the `hash` is
+/// the HMAC-SHA256 of the full key keyed with a random per-row `salt`.
+#[derive(Debug, Clone)]
+pub struct HashedApiKey {
+    pub key_id: String,
+    pub salt: String,
+    pub hash: String,
+}
+
+/// A recovery code as it is stored in the database, the HMAC-SHA256 of the
+/// code keyed with a random per-row `salt`, like an API key
+#[derive(Debug, Clone)]
+pub struct HashedCode {
+    pub salt: String,
+    pub hash: String,
+}
+
+/// Return the lookup id for an API key, which is just its prefix
+pub fn api_key_id(api_key: &str) -> &str {
+    return match api_key.char_indices().nth(API_KEY_ID_LEN) {
+        Some((i, _)) => &api_key[..i],
+        None => api_key,
+    };
+}
+
+/// Hash an API key with a new random salt for storage
+pub fn hash_api_key(api_key: &str) -> HashedApiKey {
+    let mut salt = [0u8; API_KEY_SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    return HashedApiKey {
+        key_id: api_key_id(api_key).to_string(),
+        salt: B64.encode(salt),
+        hash: B64.encode(api_key_mac(api_key, &salt).finalize().into_bytes()),
+    };
+}
+
+/// Check an API key against its stored salt and hash in constant time
+pub fn verify_api_key(api_key: &str, salt: &str, hash: &str) -> bool {
+    let (salt, hash) = match (B64.decode(salt), B64.decode(hash)) {
+        (Ok(s), Ok(h)) => (s, h),
+        _ => return false,
+    };
+
+    return api_key_mac(api_key, &salt).verify_slice(&hash).is_ok();
+}
+
+/// Hash a recovery code with a new random salt for storage
+pub fn hash_recovery_code(code: &str) -> HashedCode {
+    let hashed = hash_api_key(code);
+
+    return HashedCode {
+        salt: hashed.salt,
+        hash: hashed.hash,
+    };
+}
+
+/// Check a recovery code against its stored salt and hash in constant time
+pub fn verify_recovery_code(code: &str, salt: &str, hash: &str) -> bool {
+    return verify_api_key(code, salt, hash);
+}
+
+fn api_key_mac(api_key: &str, salt: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(salt).expect("HMAC takes a key of any size");
+    mac.update(api_key.as_bytes());
+
+    return mac;
+}
+
+/// The associated data for a token.  Owner ids are only digits, so the NUL
+/// keeps an unowned ident from looking like an owned one.
+fn token_aad(owner: Option<i64>, ident: &str) -> Vec<u8> {
+    let owner = owner.map(|o| o.to_string()).unwrap_or_default();
+
+    return format!("{}\0{}", owner, ident).into_bytes();
+}
+
+/// Encrypt the plaintext and return it base64 encoded with the nonce
+/// prepended
+fn encrypt(key: &Key<Aes256Gcm>, plaintext: &[u8], aad: &[u8]) -> Result<String> {
+    let cipher = Aes256Gcm::new(key);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ct = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| anyhow!("Encryption failed"))?;
+
+    let mut ret = nonce.to_vec();
+    ret.extend_from_slice(&ct);
+
+    return Ok(B64.encode(ret));
+}
+
+/// Decrypt a base64 encoded `nonce || ciphertext` blob
+fn decrypt(key: &Key<Aes256Gcm>, b64: &str, aad: &[u8]) -> Result<Vec<u8>> {
+    let raw = B64.decode(b64)?;
+    if raw.len() <= NONCE_LEN {
+        return Err(anyhow!("Ciphertext is too short"));
+    }
+
+    let (nonce, ct) = raw.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(key);
+
+    let ret = cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ct, aad })
+        .map_err(|_| anyhow!("Decryption failed, wrong key or corrupt data"))?;
+
+    return Ok(ret);
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn _test_cipher() -> Cipher {
+        return Cipher::new(&[7u8; KEY_LEN]).unwrap();
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let c = _test_cipher();
+        let sealed = c.seal(Some(1), "test_ident", "ABCDEFGHIJKLMNOP").unwrap();
+
+        assert_ne!(sealed.token, "ABCDEFGHIJKLMNOP");
+        assert_eq!(
+            c.open(Some(1), "test_ident", &sealed).unwrap(),
+            "ABCDEFGHIJKLMNOP"
+        );
+    }
+
+    #[test]
+    fn test_wrong_ident() {
+        let c = _test_cipher();
+        let sealed = c.seal(Some(1), "test_ident", "ABCDEFGHIJKLMNOP").unwrap();
+
+        assert!(c.open(Some(1), "other_ident", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_wrong_owner() {
+        let c = _test_cipher();
+        let sealed = c.seal(Some(1), "test_ident", "ABCDEFGHIJKLMNOP").unwrap();
+
+        assert!(c.open(Some(2), "test_ident", &sealed).is_err());
+        assert!(c.open(None, "test_ident", &sealed).is_err());
+        assert!(c.rewrap(Some(2), "test_ident", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let sealed = _test_cipher().seal(Some(1), "test_ident", "ABC").unwrap();
+        let other = Cipher::new(&[8u8; KEY_LEN]).unwrap();
+
+        assert!(other.open(Some(1), "test_ident", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_bad_key_len() {
+        assert!(Cipher::new(&[1u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_rewrap() {
+        let old = _test_cipher();
+        let sealed = old.seal(Some(1), "test_ident", "ABC").unwrap();
+        assert_eq!(sealed.key_version, 1);
+
+        let ring =
+            Cipher::from_keys(2, &[(1, vec![7u8; KEY_LEN]), (2, vec![9u8; KEY_LEN])]).unwrap();
+
+        // Both the old and rewrapped secrets should open with the keyring
+        assert_eq!(ring.open(Some(1), "test_ident", &sealed).unwrap(), "ABC");
+        let rewrapped = ring.rewrap(Some(1), "test_ident", &sealed).unwrap();
+        assert_eq!(rewrapped.key_version, 2);
+        assert_eq!(rewrapped.token, sealed.token);
+        assert_eq!(ring.open(Some(1), "test_ident", &rewrapped).unwrap(), "ABC");
+
+        // But the old key alone can no longer open it
+        assert!(old.open(Some(1), "test_ident", &rewrapped).is_err());
+    }
+
+    #[test]
+    fn test_missing_active() {
+        assert!(Cipher::from_keys(2, &[(1, vec![7u8; KEY_LEN])]).is_err());
+    }
+
+    #[test]
+    fn test_api_key_hash() {
+        let key = "abcdefgh12345678abcdefgh12345678";
+        let hashed = hash_api_key(key);
+
+        assert_eq!(hashed.key_id, "abcdefgh");
+        assert_ne!(hashed.hash, key);
+        assert!(verify_api_key(key, &hashed.salt, &hashed.hash));
+        assert!(!verify_api_key(
+            "abcdefgh00000000",
+            &hashed.salt,
+            &hashed.hash
+        ));
+
+        // The same key should get a different salt and hash each time
+        let other = hash_api_key(key);
+        assert_ne!(other.salt, hashed.salt);
+        assert_ne!(other.hash, hashed.hash);
+    }
+
+    #[test]
+    fn test_recovery_code_hash() {
+        let hashed = hash_recovery_code("ABCDE12345");
+
+        assert!(verify_recovery_code(
+            "ABCDE12345",
+            &hashed.salt,
+            &hashed.hash
+        ));
+        assert!(!verify_recovery_code(
+            "ABCDE12346",
+            &hashed.salt,
+            &hashed.hash
+        ));
+        assert!(!verify_recovery_code("ABCDE12345", "bogus", &hashed.hash));
+    }
+
+    #[test]
+    fn test_api_key_id_short() {
+        assert_eq!(api_key_id("abc"), "abc");
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut conf = Ini::new();
+        conf.read(format!(
+            "[crypto]\nkey_1 = {}\nkey_2 = {}\n",
+            B64.encode([7u8; KEY_LEN]),
+            B64.encode([9u8; KEY_LEN])
+        ))
+        .unwrap();
+
+        let c = Cipher::from_config(&conf).unwrap();
+        assert_eq!(c.active_version(), 2);
+    }
+
+    #[test]
+    fn test_from_config_duplicate_version() {
+        let key = B64.encode([7u8; KEY_LEN]);
+        let path = std::env::temp_dir().join(format!("gauth-test-{}.key", std::process::id()));
+        fs::write(&path, &key).unwrap();
+        let dups = [
+            format!("[crypto]\nmaster_key = {}\nkey_1 = {}\n", key, key),
+            format!(
+                "[crypto]\nkey_2 = {}\nkey_2_file = {}\n",
+                key,
+                path.display()
+            ),
+        ];
+
+        for dup in dups {
+            let mut conf = Ini::new();
+            conf.read(dup).unwrap();
+
+            let err = Cipher::from_config(&conf).err().unwrap();
+            assert!(err.to_string().contains("is set by both"), "{}", err);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_from_config_bad_name() {
+        let key = B64.encode([7u8; KEY_LEN]);
+
+        for name in ["key_foo", "key_foo_file", "key_"] {
+            let mut conf = Ini::new();
+            conf.read(format!("[crypto]\n{} = {}\n", name, key))
+                .unwrap();
+
+            let err = Cipher::from_config(&conf).err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid master key option {}", name)
+            );
+        }
+    }
+}
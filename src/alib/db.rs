@@ -1,168 +1,1273 @@
-use anyhow::Result;
-use postgres::{Client, NoTls};
+use super::crypto::{api_key_id, hash_api_key, verify_api_key, Cipher, HashedCode, Sealed};
+use super::migrate::{self, Migration};
+use super::ratelimit::RateLimit;
+use super::scope::Scope;
+use super::store::{
+    AmbiguousIdent, ApiKey, ApiKeyStore, DbUnavailable, DuplicateIdent, MigrationStore,
+    RecoveryCode, Secret, SecretStore, UnknownApiKey, UnknownIdent,
+};
+use super::totp::{OtpType, TotpParams};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bb8::{Pool, PooledConnection};
+use bb8_postgres::PostgresConnectionManager;
+use configparser::ini::Ini;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{NoTls, Row};
 
+type Manager = PostgresConnectionManager<NoTls>;
+
+/// How often, in seconds, to update the last used time for an API key
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// The longest to wait between attempts to connect at startup
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// The advisory lock held while applying a migration, so servers starting
+/// at the same time don't both try to apply it
+const MIGRATION_LOCK_ID: i64 = 0x0067_6175_7468;
+
+const MIGRATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version BIGINT PRIMARY KEY,
+        name VARCHAR(256) NOT NULL,
+        applied_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+    )";
+
+/// The settings for the connection pool, from the `[db]` config section
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// The maximum number of connections to open
+    pub size: u32,
+    /// The number of idle connections to keep open, defaulting to `size`
+    pub min_idle: Option<u32>,
+    /// How long a request waits for a free connection before failing
+    pub timeout: Duration,
+    /// Close connections that have been idle this long, down to `min_idle`
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        return Self {
+            size: 10,
+            min_idle: None,
+            timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(600)),
+        };
+    }
+}
+
+impl PoolConfig {
+    pub fn from_config(conf: &Ini) -> Self {
+        let def = Self::default();
+        let idle = conf
+            .getuint("db", "pool_idle_timeout")
+            .unwrap()
+            .unwrap_or(600);
+
+        return Self {
+            size: conf
+                .getuint("db", "pool_size")
+                .unwrap()
+                .unwrap_or(def.size as u64) as u32,
+            min_idle: conf
+                .getuint("db", "pool_min_idle")
+                .unwrap()
+                .map(|n| n as u32),
+            timeout: Duration::from_secs(conf.getuint("db", "pool_timeout").unwrap().unwrap_or(5)),
+            // 0 keeps idle connections open forever
+            idle_timeout: Some(idle).filter(|&i| i > 0).map(Duration::from_secs),
+        };
+    }
+}
+
+/// The Postgres implementation of the stores.  Every call checks out its own
+/// connection from a pool, so requests don't wait on each other.  Broken
+/// connections are detected when they are checked out and replaced.
 pub struct DB {
-    pub client: Client,
+    pool: Pool<Manager>,
+    cipher: Cipher,
 }
 
 impl DB {
-    pub fn new(params: &str) -> Self {
-        let client = Client::connect(params, NoTls).unwrap();
-        return DB { client };
+    /// Create the pool.  This fails if the initial `min_idle` connections
+    /// can't be opened, so an unreachable db is caught at startup.
+    pub async fn new(params: &str, cipher: Cipher, pool: &PoolConfig) -> Result<Self> {
+        let manager = PostgresConnectionManager::new(params.parse()?, NoTls);
+        let pool = Pool::builder()
+            .max_size(pool.size)
+            .min_idle(pool.min_idle.unwrap_or(pool.size))
+            .connection_timeout(pool.timeout)
+            .idle_timeout(pool.idle_timeout)
+            .build(manager)
+            .await?;
+
+        return Ok(DB { pool, cipher });
     }
 
-    /*
-     * Begin authentication methods
-     */
-    pub fn add_api_key(&mut self, host: &str, api_key: &str) -> Result<()> {
-        let q = "INSERT INTO loc_auth (host, api_key) VALUES ($1, $2)";
+    /// Like `new()`, but if the db can't be reached, keep trying with
+    /// backoff for up to `retry`.  This lets the server start before the
+    /// db is up.
+    pub async fn connect(
+        params: &str,
+        cipher: Cipher,
+        pool: &PoolConfig,
+        retry: Duration,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let mut delay = Duration::from_secs(1);
 
-        self.client.execute(q, &[&host, &api_key])?;
+        loop {
+            match Self::new(params, cipher.clone(), pool).await {
+                Ok(db) => return Ok(db),
+                Err(e) if start.elapsed() + delay < retry => {
+                    warn!(
+                        "Failed to connect to the db, retrying in {}s: {}",
+                        delay.as_secs(),
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-        return Ok(());
+    /// Check out a connection from the pool.  The pool replaces broken
+    /// connections, so this only fails if the db is unreachable.
+    async fn client(&self) -> Result<PooledConnection<'_, Manager>> {
+        return self
+            .pool
+            .get()
+            .await
+            .map_err(|e| DbUnavailable(e.to_string()).into());
+    }
+
+    /// Decrypt a token read from the db.  Rows written before encryption was
+    /// added won't have a data key and are returned as is until they are
+    /// migrated.
+    fn unseal(&self, ident: &str, row: &Row) -> Result<String> {
+        let token: String = row.get("token");
+        let dek = match row.get::<_, Option<String>>("dek") {
+            Some(d) => d,
+            None => {
+                warn!("Secret for {} is stored in plaintext", ident);
+                return Ok(token);
+            }
+        };
+
+        return self.cipher.open(
+            row.get("owner_id"),
+            ident,
+            &Sealed {
+                dek,
+                token,
+                key_version: row.get("key_version"),
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for DB {
+    async fn add_api_key(
+        &self,
+        host: &str,
+        api_key: &str,
+        rate_limit: Option<&RateLimit>,
+        scopes: &[Scope],
+        expires_at: Option<i64>,
+    ) -> Result<i64> {
+        let q = "INSERT INTO loc_auth \
+            (host, key_id, key_salt, key_hash, rate_limit, rate_burst, scopes, \
+            created_at, expires_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id";
+        let now = chrono::Utc::now().timestamp();
+        let hashed = hash_api_key(api_key);
+        let rate = rate_limit.map(|l| l.rate);
+        let burst = rate_limit.map(|l| l.burst as i32);
+        let scopes = Scope::join(scopes);
+
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                q,
+                &[
+                    &host,
+                    &hashed.key_id,
+                    &hashed.salt,
+                    &hashed.hash,
+                    &rate,
+                    &burst,
+                    &scopes,
+                    &now,
+                    &expires_at,
+                ],
+            )
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(row.get("id"));
     }
 
-    pub fn api_key_exists(&mut self, api_key: &str) -> bool {
-        let q = "SELECT host FROM loc_auth WHERE api_key = $1";
+    /// Look up and authenticate an API key.  Only the key's prefix is stored
+    /// in plaintext, so this finds the candidate rows by that and then checks
+    /// the full key against each stored hash.  Note that this will return
+    /// revoked and expired keys, so check `ApiKey::is_active()`.
+    async fn get_api_key(&self, api_key: &str) -> Result<ApiKey> {
+        let q = "SELECT * FROM loc_auth WHERE key_id = $1";
+
+        for row in self
+            .client()
+            .await?
+            .query(q, &[&api_key_id(api_key)])
+            .await
+            .map_err(pg_err)?
+        {
+            let salt: String = row.get("key_salt");
+            let hash: String = row.get("key_hash");
 
-        if let Ok(_) = self.client.query_one(q, &[&api_key]) {
-            return true;
+            if verify_api_key(api_key, &salt, &hash) {
+                return api_key_from_row(&row);
+            }
         }
 
-        return false;
+        return Err(anyhow!("Invalid api key"));
+    }
+
+    async fn get_api_key_by_id(&self, id: i64) -> Result<ApiKey> {
+        let q = "SELECT * FROM loc_auth WHERE id = $1 AND key_hash IS NOT NULL";
+
+        return match self
+            .client()
+            .await?
+            .query_opt(q, &[&id])
+            .await
+            .map_err(pg_err)?
+        {
+            Some(row) => api_key_from_row(&row),
+            None => Err(UnknownApiKey.into()),
+        };
+    }
+
+    async fn get_api_key_by_cert(&self, names: &[String]) -> Result<ApiKey> {
+        let q = "SELECT * FROM loc_auth WHERE cert_name = $1 AND key_hash IS NOT NULL";
+        let client = self.client().await?;
+
+        for name in names {
+            if let Some(row) = client.query_opt(q, &[name]).await.map_err(pg_err)? {
+                return api_key_from_row(&row);
+            }
+        }
+
+        return Err(anyhow!("No api key for the client certificate"));
+    }
+
+    async fn set_api_key_cert(&self, id: i64, name: Option<&str>) -> Result<bool> {
+        let q = "UPDATE loc_auth SET cert_name = $1 WHERE id = $2";
+
+        return Ok(self
+            .client()
+            .await?
+            .execute(q, &[&name, &id])
+            .await
+            .map_err(pg_err)?
+            > 0);
     }
 
-    pub fn get_host_for_api_key(&mut self, api_key: &str) -> Result<String> {
-        let q = "SELECT host FROM loc_auth WHERE api_key = $1";
+    async fn set_api_key_expiry(&self, id: i64, expires_at: Option<i64>) -> Result<bool> {
+        let q = "UPDATE loc_auth SET expires_at = $1 WHERE id = $2";
 
-        let row = self.client.query_one(q, &[&api_key])?;
+        return Ok(self
+            .client()
+            .await?
+            .execute(q, &[&expires_at, &id])
+            .await
+            .map_err(pg_err)?
+            > 0);
+    }
+
+    /// List all the api keys, including revoked and expired ones.  Keys that
+    /// still need to be migrated by `hash_plaintext_api_keys()` are skipped.
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let q = "SELECT * FROM loc_auth WHERE key_hash IS NOT NULL ORDER BY id";
 
-        let ret: String = row.get("host");
+        let mut ret = vec![];
+        for row in self.client().await?.query(q, &[]).await.map_err(pg_err)? {
+            ret.push(api_key_from_row(&row)?);
+        }
 
         return Ok(ret);
     }
 
-    /*
-     * Begin secret methods
-     */
-    pub fn create_secret(&mut self, ident: &str, secret: &str) -> Result<()> {
-        let q = "INSERT INTO secrets (ident, token) VALUES ($1, $2)";
+    async fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        let q = "UPDATE loc_auth SET revoked = TRUE WHERE id = $1";
+
+        return Ok(self
+            .client()
+            .await?
+            .execute(q, &[&id])
+            .await
+            .map_err(pg_err)?
+            > 0);
+    }
+
+    async fn rotate_api_key(&self, id: i64, api_key: &str) -> Result<bool> {
+        let q = "UPDATE loc_auth SET key_id = $1, key_salt = $2, key_hash = $3 \
+            WHERE id = $4 AND NOT revoked";
+        let hashed = hash_api_key(api_key);
+
+        let count = self
+            .client()
+            .await?
+            .execute(q, &[&hashed.key_id, &hashed.salt, &hashed.hash, &id])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(count > 0);
+    }
+
+    /// Record that an api key was just used.  This only writes to the db if
+    /// the last update was a while ago so that busy keys don't cause a write
+    /// on every request.
+    async fn touch_api_key(&self, id: i64) -> Result<()> {
+        let q = "UPDATE loc_auth SET last_used_at = $2 \
+            WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < $3)";
+        let now = chrono::Utc::now().timestamp();
+
+        self.client()
+            .await?
+            .execute(q, &[&id, &now, &(now - LAST_USED_RESOLUTION)])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(());
+    }
+
+    async fn hash_plaintext_api_keys(&self) -> Result<u64> {
+        let q = "SELECT id, api_key FROM loc_auth \
+            WHERE key_hash IS NULL AND api_key IS NOT NULL";
+        let upd = "UPDATE loc_auth SET key_id = $1, key_salt = $2, key_hash = $3, \
+            api_key = NULL WHERE id = $4";
+
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(pg_err)?;
+        let mut count = 0;
+
+        for row in tx.query(q, &[]).await.map_err(pg_err)? {
+            let id: i64 = row.get("id");
+            let api_key: String = row.get("api_key");
+            let hashed = hash_api_key(&api_key);
+
+            count += tx
+                .execute(upd, &[&hashed.key_id, &hashed.salt, &hashed.hash, &id])
+                .await
+                .map_err(pg_err)?;
+        }
+
+        tx.commit().await.map_err(pg_err)?;
 
-        self.client.execute(q, &[&ident, &secret])?;
+        return Ok(count);
+    }
+}
+
+#[async_trait]
+impl SecretStore for DB {
+    async fn create_secret(
+        &self,
+        owner: i64,
+        ident: &str,
+        secret: &str,
+        params: &TotpParams,
+        kind: OtpType,
+    ) -> Result<()> {
+        let q = "INSERT INTO secrets (owner_id, ident, token, dek, key_version, \
+            digits, period, algorithm, type) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)";
+        let sealed = self.cipher.seal(Some(owner), ident, secret)?;
+
+        let res = self
+            .client()
+            .await?
+            .execute(
+                q,
+                &[
+                    &owner,
+                    &ident,
+                    &sealed.token,
+                    &sealed.dek,
+                    &sealed.key_version,
+                    &(params.digits as i32),
+                    &(params.period as i32),
+                    &params.algorithm.to_string(),
+                    &kind.to_string(),
+                ],
+            )
+            .await;
+
+        if let Err(e) = res {
+            if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+                return Err(DuplicateIdent.into());
+            }
+            return Err(pg_err(e));
+        }
 
         return Ok(());
     }
 
-    pub fn delete_secret(&mut self, ident: &str) -> Result<()> {
-        let q = "DELETE FROM secrets WHERE ident = $1";
+    async fn delete_secret(&self, owner: Option<i64>, ident: &str) -> Result<()> {
+        // The subquery makes sure this fails rather than deleting the
+        // idents of multiple owners at once
+        let q = "DELETE FROM secrets WHERE id = (SELECT id FROM secrets \
+            WHERE ident = $1 AND ($2::BIGINT IS NULL OR owner_id = $2))";
+
+        let res = self.client().await?.execute(q, &[&ident, &owner]).await;
 
-        self.client.execute(q, &[&ident])?;
+        let count = match res {
+            Ok(c) => c,
+            Err(e) if e.code() == Some(&SqlState::CARDINALITY_VIOLATION) => {
+                return Err(AmbiguousIdent.into());
+            }
+            Err(e) => return Err(pg_err(e)),
+        };
+
+        if count == 0 {
+            return Err(UnknownIdent.into());
+        }
 
         return Ok(());
     }
 
-    pub fn get_secret(&mut self, ident: &str) -> Result<(i64, String)> {
-        let q = "SELECT id, token FROM secrets WHERE ident = $1";
+    async fn get_secret(&self, owner: Option<i64>, ident: &str) -> Result<Secret> {
+        let q = "SELECT id, owner_id, token, dek, key_version, digits, period, \
+            algorithm, type, counter \
+            FROM secrets WHERE ident = $1 AND ($2::BIGINT IS NULL OR owner_id = $2)";
 
-        let row = self.client.query_one(q, &[&ident])?;
-        let token: String = row.get("token");
-        let id: i64 = row.get("id");
+        let rows = self
+            .client()
+            .await?
+            .query(q, &[&ident, &owner])
+            .await
+            .map_err(pg_err)?;
+        let row = match rows.as_slice() {
+            [] => return Err(UnknownIdent.into()),
+            [row] => row,
+            _ => return Err(AmbiguousIdent.into()),
+        };
 
-        return Ok((id, token));
+        return Ok(Secret {
+            id: row.get("id"),
+            secret: self.unseal(ident, row)?,
+            params: TotpParams {
+                digits: row.get::<_, i32>("digits") as u32,
+                period: row.get::<_, i32>("period") as u64,
+                algorithm: row.get::<_, String>("algorithm").parse()?,
+            },
+            kind: row.get::<_, String>("type").parse()?,
+            counter: row.get("counter"),
+        });
     }
 
-    #[allow(dead_code)]
-    pub fn get_secret_by_id(&mut self, id: i64) -> Result<(String, String)> {
-        let q = "SELECT ident, token FROM secrets WHERE id = $1";
+    async fn get_secret_by_id(&self, id: i64) -> Result<(String, String)> {
+        let q = "SELECT ident, owner_id, token, dek, key_version FROM secrets WHERE id = $1";
 
-        let row = self.client.query_one(q, &[&id])?;
-        let token: String = row.get("token");
+        let row = self
+            .client()
+            .await?
+            .query_one(q, &[&id])
+            .await
+            .map_err(pg_err)?;
         let ident: String = row.get("ident");
+        let token = self.unseal(&ident, &row)?;
 
         return Ok((ident, token));
     }
+
+    async fn assign_unowned_secrets(&self, owner: i64) -> Result<u64> {
+        // Plaintext rows just need the owner, they're sealed for it later
+        let plain = "UPDATE secrets SET owner_id = $1 WHERE owner_id IS NULL AND dek IS NULL";
+        let q = "SELECT id, ident, owner_id, token, dek, key_version FROM secrets \
+            WHERE owner_id IS NULL FOR UPDATE";
+        let upd = "UPDATE secrets SET owner_id = $1, token = $2, dek = $3, key_version = $4 \
+            WHERE id = $5";
+
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(pg_err)?;
+        let mut count = tx.execute(plain, &[&owner]).await.map_err(pg_err)?;
+
+        // The owner is bound to sealed tokens, so they have to be sealed again
+        for row in tx.query(q, &[]).await.map_err(pg_err)? {
+            let id: i64 = row.get("id");
+            let ident: String = row.get("ident");
+            let token = self.unseal(&ident, &row)?;
+            let sealed = self.cipher.seal(Some(owner), &ident, &token)?;
+
+            count += tx
+                .execute(
+                    upd,
+                    &[&owner, &sealed.token, &sealed.dek, &sealed.key_version, &id],
+                )
+                .await
+                .map_err(pg_err)?;
+        }
+
+        tx.commit().await.map_err(pg_err)?;
+
+        return Ok(count);
+    }
+
+    async fn use_step(&self, id: i64, step: i64) -> Result<bool> {
+        let q = "UPDATE secrets SET last_step = $2 \
+            WHERE id = $1 AND (last_step IS NULL OR last_step < $2)";
+
+        let count = self
+            .client()
+            .await?
+            .execute(q, &[&id, &step])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(count > 0);
+    }
+
+    async fn use_counter(&self, id: i64, counter: i64) -> Result<bool> {
+        let q = "UPDATE secrets SET counter = $2 + 1 WHERE id = $1 AND counter <= $2";
+
+        let count = self
+            .client()
+            .await?
+            .execute(q, &[&id, &counter])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(count > 0);
+    }
+
+    async fn set_recovery_codes(&self, id: i64, codes: &[HashedCode]) -> Result<()> {
+        let del = "DELETE FROM recovery_codes WHERE secret_id = $1";
+        let ins = "INSERT INTO recovery_codes (secret_id, code_salt, code_hash) \
+            VALUES ($1, $2, $3)";
+
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(pg_err)?;
+
+        tx.execute(del, &[&id]).await.map_err(pg_err)?;
+        for code in codes {
+            tx.execute(ins, &[&id, &code.salt, &code.hash])
+                .await
+                .map_err(pg_err)?;
+        }
+
+        tx.commit().await.map_err(pg_err)?;
+
+        return Ok(());
+    }
+
+    async fn get_recovery_codes(&self, id: i64) -> Result<Vec<RecoveryCode>> {
+        let q = "SELECT id, code_salt, code_hash FROM recovery_codes \
+            WHERE secret_id = $1 AND used_at IS NULL ORDER BY id";
+
+        let rows = self
+            .client()
+            .await?
+            .query(q, &[&id])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(rows
+            .iter()
+            .map(|r| RecoveryCode {
+                id: r.get("id"),
+                hashed: HashedCode {
+                    salt: r.get("code_salt"),
+                    hash: r.get("code_hash"),
+                },
+            })
+            .collect());
+    }
+
+    async fn use_recovery_code(&self, code_id: i64) -> Result<bool> {
+        let q = "UPDATE recovery_codes SET used_at = EXTRACT(EPOCH FROM NOW())::BIGINT \
+            WHERE id = $1 AND used_at IS NULL";
+
+        let count = self
+            .client()
+            .await?
+            .execute(q, &[&code_id])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(count > 0);
+    }
+
+    async fn get_locked_until(&self, id: i64) -> Result<Option<i64>> {
+        let q = "SELECT locked_until FROM secrets WHERE id = $1";
+
+        let row = self
+            .client()
+            .await?
+            .query_one(q, &[&id])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(row.get("locked_until"));
+    }
+
+    async fn record_verify_failure(&self, id: i64) -> Result<i32> {
+        let q = "UPDATE secrets SET failed_attempts = failed_attempts + 1 \
+            WHERE id = $1 RETURNING failed_attempts";
+
+        let row = self
+            .client()
+            .await?
+            .query_one(q, &[&id])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(row.get("failed_attempts"));
+    }
+
+    async fn lock_ident(&self, id: i64, until: i64) -> Result<()> {
+        let q = "UPDATE secrets SET locked_until = $2 WHERE id = $1";
+
+        self.client()
+            .await?
+            .execute(q, &[&id, &until])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(());
+    }
+
+    async fn reset_verify_failures(&self, id: i64) -> Result<()> {
+        let q = "UPDATE secrets SET failed_attempts = 0, locked_until = NULL \
+            WHERE id = $1";
+
+        self.client()
+            .await?
+            .execute(q, &[&id])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(());
+    }
+
+    /// Return the number of encrypted secrets that are not yet sealed with
+    /// the active master key
+    async fn count_stale_secrets(&self) -> Result<i64> {
+        let q = "SELECT COUNT(*) AS cnt FROM secrets \
+            WHERE dek IS NOT NULL AND key_version <> $1";
+
+        let row = self
+            .client()
+            .await?
+            .query_one(q, &[&self.cipher.active_version()])
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(row.get("cnt"));
+    }
+
+    /// Rewrap the data keys for up to `batch_size` secrets that are sealed
+    /// with an older master key, returning the number of rows updated.  Each
+    /// batch is its own transaction and locked rows are skipped, so this is
+    /// safe to run against a live server.
+    async fn rotate_secrets_batch(&self, batch_size: i64) -> Result<u64> {
+        let q = "SELECT id, ident, owner_id, token, dek, key_version FROM secrets \
+            WHERE dek IS NOT NULL AND key_version <> $1 \
+            ORDER BY id LIMIT $2 FOR UPDATE SKIP LOCKED";
+        let upd = "UPDATE secrets SET dek = $1, key_version = $2 WHERE id = $3";
+
+        let active = self.cipher.active_version();
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(pg_err)?;
+        let mut count = 0;
+
+        for row in tx.query(q, &[&active, &batch_size]).await.map_err(pg_err)? {
+            let id: i64 = row.get("id");
+            let ident: String = row.get("ident");
+            let sealed = Sealed {
+                dek: row.get("dek"),
+                token: row.get("token"),
+                key_version: row.get("key_version"),
+            };
+            let sealed = self.cipher.rewrap(row.get("owner_id"), &ident, &sealed)?;
+
+            count += tx
+                .execute(upd, &[&sealed.dek, &sealed.key_version, &id])
+                .await
+                .map_err(pg_err)?;
+        }
+
+        tx.commit().await.map_err(pg_err)?;
+
+        return Ok(count);
+    }
+
+    async fn encrypt_plaintext_secrets(&self) -> Result<u64> {
+        let q = "SELECT id, ident, owner_id, token FROM secrets WHERE dek IS NULL";
+        let upd = "UPDATE secrets SET token = $1, dek = $2, key_version = $3 \
+            WHERE id = $4 AND dek IS NULL";
+
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(pg_err)?;
+        let mut count = 0;
+
+        for row in tx.query(q, &[]).await.map_err(pg_err)? {
+            let id: i64 = row.get("id");
+            let ident: String = row.get("ident");
+            let token: String = row.get("token");
+            let sealed = self.cipher.seal(row.get("owner_id"), &ident, &token)?;
+
+            count += tx
+                .execute(upd, &[&sealed.token, &sealed.dek, &sealed.key_version, &id])
+                .await
+                .map_err(pg_err)?;
+        }
+
+        tx.commit().await.map_err(pg_err)?;
+
+        return Ok(count);
+    }
+}
+
+#[async_trait]
+impl MigrationStore for DB {
+    fn migrations(&self) -> &'static [Migration] {
+        return migrate::POSTGRES;
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<i64>> {
+        let client = self.client().await?;
+
+        // Don't create the table here, so a dry run doesn't change anything
+        let exists: bool = client
+            .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+            .await
+            .map_err(pg_err)?
+            .get(0);
+        if !exists {
+            return Ok(vec![]);
+        }
+
+        let rows = client
+            .query(
+                "SELECT version FROM schema_migrations ORDER BY version",
+                &[],
+            )
+            .await
+            .map_err(pg_err)?;
+
+        return Ok(rows.iter().map(|r| r.get(0)).collect());
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await.map_err(pg_err)?;
+
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID])
+            .await
+            .map_err(pg_err)?;
+        tx.batch_execute(MIGRATIONS_TABLE).await.map_err(pg_err)?;
+
+        let done = tx
+            .query_opt(
+                "SELECT 1 FROM schema_migrations WHERE version = $1",
+                &[&migration.version],
+            )
+            .await
+            .map_err(pg_err)?
+            .is_some();
+        if done {
+            return Ok(false);
+        }
+
+        tx.batch_execute(migration.sql).await.map_err(pg_err)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        )
+        .await
+        .map_err(pg_err)?;
+        tx.commit().await.map_err(pg_err)?;
+
+        return Ok(true);
+    }
+}
+
+/// Convert a postgres error, flagging the ones caused by a lost connection
+/// as `DbUnavailable` so they can be told apart from bad queries
+fn pg_err(e: tokio_postgres::Error) -> anyhow::Error {
+    let io_err = e.source().is_some_and(|s| s.is::<std::io::Error>());
+
+    if e.is_closed() || io_err {
+        return DbUnavailable(e.to_string()).into();
+    }
+
+    return e.into();
+}
+
+/// Build an `ApiKey` from a full `loc_auth` row
+fn api_key_from_row(row: &Row) -> Result<ApiKey> {
+    let rate: Option<f64> = row.get("rate_limit");
+    let burst: Option<i32> = row.get("rate_burst");
+    let scopes: String = row.get("scopes");
+
+    return Ok(ApiKey {
+        id: row.get("id"),
+        host: row.get("host"),
+        key_id: row.get("key_id"),
+        // If a burst isn't set, allow at least a second's worth
+        rate_limit: rate.map(|r| RateLimit {
+            rate: r,
+            burst: burst.unwrap_or(r.ceil().max(1.0) as i32) as u32,
+        }),
+        scopes: Scope::parse_list(&scopes)?,
+        cert_name: row.get("cert_name"),
+        created_at: row.get("created_at"),
+        last_used_at: row.get("last_used_at"),
+        expires_at: row.get("expires_at"),
+        revoked: row.get("revoked"),
+    });
 }
 
 /*
  * Unit tests
  */
+#[cfg(test)]
 mod t {
     use super::*;
+    use crate::alib::crypto::hash_recovery_code;
+    use crate::alib::totp::Algorithm;
 
-    fn _test_setup() -> DB {
+    async fn _test_setup() -> DB {
         let params = "host=fserver.splitstreams.com \
             port=5432 \
             user=test \
             password=c_3ZKNpDAq272CPR5FOx2jOb72C1I-lV \
             dbname=testing \
             sslmode=prefer";
-        let conn = DB::new(params);
+        let cipher = Cipher::new(&[0u8; 32]).unwrap();
+        let conn = DB::new(params, cipher, &PoolConfig::default())
+            .await
+            .unwrap();
+        migrate::run(&conn).await.unwrap();
 
         return conn;
     }
 
-    fn _test_cleanup(conn: &mut DB) {
-        conn.client.execute("DELETE FROM secrets", &[]).unwrap();
-        conn.client.execute("DELETE FROM loc_auth", &[]).unwrap();
+    async fn _test_cleanup(conn: &DB) {
+        conn.client()
+            .await
+            .unwrap()
+            .execute("DELETE FROM secrets", &[])
+            .await
+            .unwrap();
+        conn.client()
+            .await
+            .unwrap()
+            .execute("DELETE FROM loc_auth", &[])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_retry() {
+        let cipher = Cipher::new(&[0u8; 32]).unwrap();
+        let pool = PoolConfig {
+            size: 1,
+            timeout: Duration::from_secs(1),
+            ..PoolConfig::default()
+        };
+        let start = Instant::now();
+
+        // Nothing listens on port 1, so this should retry and then give up
+        let res = DB::connect(
+            "host=127.0.0.1 port=1 user=test",
+            cipher,
+            &pool,
+            Duration::from_secs(3),
+        )
+        .await;
+        assert!(res.is_err());
+        assert!(start.elapsed() >= Duration::from_secs(2));
     }
 
-    #[test]
-    fn test_connection() {
-        let mut conn = _test_setup();
-        _test_cleanup(&mut conn);
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_connection() {
+        let conn = _test_setup().await;
+        _test_cleanup(&conn).await;
     }
 
-    #[test]
-    fn test_secrets() {
-        let mut conn = _test_setup();
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_secrets() {
+        let mut conn = _test_setup().await;
 
         let ident = "test_ident";
         let secret = "abc123";
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+        let other = conn
+            .add_api_key(
+                "other.example.com",
+                "def67890",
+                None,
+                &[Scope::Create],
+                None,
+            )
+            .await
+            .unwrap();
 
-        let res = conn.create_secret(ident, secret);
+        let res = conn
+            .create_secret(owner, ident, secret, &TotpParams::default(), OtpType::Totp)
+            .await;
         if let Err(e) = res {
             panic!("Error: {}", e);
         }
         assert!(res.is_ok());
 
         // Test a duplicate secret
-        let res = conn.create_secret(ident, secret);
-        assert!(res.is_err());
+        let res = conn
+            .create_secret(owner, ident, secret, &TotpParams::default(), OtpType::Totp)
+            .await;
+        assert!(res.unwrap_err().downcast_ref::<DuplicateIdent>().is_some());
 
-        let (id, token) = conn.get_secret(ident).unwrap();
+        let sec = conn.get_secret(Some(owner), ident).await.unwrap();
+        let (id, token) = (sec.id, sec.secret);
         assert_eq!(token, secret);
 
-        let (ret_ident, ret_token) = conn.get_secret_by_id(id).unwrap();
+        // Other owners can't see it, but a lookup for any owner can
+        assert!(conn.get_secret(Some(other), ident).await.is_err());
+        assert_eq!(conn.get_secret(None, ident).await.unwrap().id, id);
+        let err = conn.delete_secret(Some(other), ident).await.unwrap_err();
+        assert!(err.is::<UnknownIdent>());
+        assert!(conn.get_secret(Some(owner), ident).await.is_ok());
+
+        // The stored token should not be the plaintext secret
+        let row = conn
+            .client()
+            .await
+            .unwrap()
+            .query_one("SELECT token FROM secrets WHERE id = $1", &[&id])
+            .await
+            .unwrap();
+        let raw: String = row.get("token");
+        assert_ne!(raw, secret);
+
+        // Rotating to a new master key should leave the secret readable
+        conn.cipher = Cipher::from_keys(2, &[(1, vec![0u8; 32]), (2, vec![1u8; 32])]).unwrap();
+        assert_eq!(conn.count_stale_secrets().await.unwrap(), 1);
+        assert_eq!(conn.rotate_secrets_batch(10).await.unwrap(), 1);
+        assert_eq!(conn.count_stale_secrets().await.unwrap(), 0);
+        assert_eq!(
+            conn.get_secret(Some(owner), ident).await.unwrap().secret,
+            secret
+        );
+
+        let (ret_ident, ret_token) = conn.get_secret_by_id(id).await.unwrap();
         assert_eq!(ret_ident, ident);
         assert_eq!(ret_token, secret);
 
-        let res = conn.delete_secret(ident);
+        // A time step can only be used once
+        assert!(conn.use_step(id, 100).await.unwrap());
+        assert!(!conn.use_step(id, 100).await.unwrap());
+        assert!(!conn.use_step(id, 99).await.unwrap());
+        assert!(conn.use_step(id, 101).await.unwrap());
+
+        // Failures should count up until they are reset
+        assert_eq!(conn.record_verify_failure(id).await.unwrap(), 1);
+        assert_eq!(conn.record_verify_failure(id).await.unwrap(), 2);
+        assert_eq!(conn.get_locked_until(id).await.unwrap(), None);
+        conn.lock_ident(id, 12345).await.unwrap();
+        assert_eq!(conn.get_locked_until(id).await.unwrap(), Some(12345));
+        conn.reset_verify_failures(id).await.unwrap();
+        assert_eq!(conn.get_locked_until(id).await.unwrap(), None);
+        assert_eq!(conn.record_verify_failure(id).await.unwrap(), 1);
+
+        // The same ident can exist for another owner
+        conn.create_secret(
+            other,
+            ident,
+            "def456",
+            &TotpParams::default(),
+            OtpType::Totp,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            conn.get_secret(Some(other), ident).await.unwrap().secret,
+            "def456"
+        );
+        let err = conn.get_secret(None, ident).await.unwrap_err();
+        assert!(err.is::<AmbiguousIdent>());
+        let err = conn.delete_secret(None, ident).await.unwrap_err();
+        assert!(err.is::<AmbiguousIdent>());
+
+        let res = conn.delete_secret(Some(owner), ident).await;
 
         assert!(res.is_ok());
 
-        let res = conn.get_secret(ident);
+        let res = conn.get_secret(Some(owner), ident).await;
 
         assert!(res.is_err());
 
-        _test_cleanup(&mut conn);
+        // A token copied into another owner's row won't open there.  Tokens
+        // are unique, so the original row has to go first.
+        for (id, secret) in [(owner, "ghi789"), (other, "jkl012")] {
+            conn.create_secret(
+                id,
+                "copied_ident",
+                secret,
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        }
+        let client = conn.client().await.unwrap();
+        let row = client
+            .query_one(
+                "DELETE FROM secrets WHERE owner_id = $1 AND ident = $2 RETURNING token, dek",
+                &[&owner, &"copied_ident"],
+            )
+            .await
+            .unwrap();
+        client
+            .execute(
+                "UPDATE secrets SET token = $1, dek = $2 WHERE owner_id = $3 AND ident = $4",
+                &[
+                    &row.get::<_, String>("token"),
+                    &row.get::<_, String>("dek"),
+                    &other,
+                    &"copied_ident",
+                ],
+            )
+            .await
+            .unwrap();
+        drop(client);
+        assert!(conn.get_secret(Some(other), "copied_ident").await.is_err());
+
+        _test_cleanup(&conn).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_totp_params() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+        let params = TotpParams {
+            digits: 8,
+            period: 60,
+            algorithm: Algorithm::Sha512,
+        };
+
+        conn.create_secret(owner, "test_ident", "abc123", &params, OtpType::Totp)
+            .await
+            .unwrap();
+        let sec = conn.get_secret(Some(owner), "test_ident").await.unwrap();
+        assert_eq!(sec.params, params);
+
+        _test_cleanup(&conn).await;
     }
 
-    #[test]
-    fn test_api_key() {
-        let mut conn = _test_setup();
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_recovery_codes() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+
+        conn.create_secret(
+            owner,
+            "test_ident",
+            "abc123",
+            &TotpParams::default(),
+            OtpType::Totp,
+        )
+        .await
+        .unwrap();
+        let id = conn.get_secret(Some(owner), "test_ident").await.unwrap().id;
+
+        let hashed = [hash_recovery_code("AAAAA"), hash_recovery_code("BBBBB")];
+        conn.set_recovery_codes(id, &hashed).await.unwrap();
+        let codes = conn.get_recovery_codes(id).await.unwrap();
+        assert_eq!(codes.len(), 2);
+        assert_eq!(codes[0].hashed.hash, hashed[0].hash);
+
+        assert!(conn.use_recovery_code(codes[0].id).await.unwrap());
+        assert!(!conn.use_recovery_code(codes[0].id).await.unwrap());
+        assert_eq!(conn.get_recovery_codes(id).await.unwrap().len(), 1);
+
+        // New codes replace all the old ones
+        conn.set_recovery_codes(id, &hashed[..1]).await.unwrap();
+        assert!(!conn.use_recovery_code(codes[1].id).await.unwrap());
+        let codes = conn.get_recovery_codes(id).await.unwrap();
+        assert_eq!(codes.len(), 1);
+
+        // And they go with the secret
+        conn.delete_secret(Some(owner), "test_ident").await.unwrap();
+        assert!(!conn.use_recovery_code(codes[0].id).await.unwrap());
+
+        _test_cleanup(&conn).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_hotp_counter() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+
+        conn.create_secret(
+            owner,
+            "hotp_ident",
+            "abc123",
+            &TotpParams::default(),
+            OtpType::Hotp,
+        )
+        .await
+        .unwrap();
+
+        let sec = conn.get_secret(Some(owner), "hotp_ident").await.unwrap();
+        assert_eq!(sec.kind, OtpType::Hotp);
+        assert_eq!(sec.counter, 0);
+
+        // Skipping ahead is fine, but never going back
+        assert!(conn.use_counter(sec.id, 3).await.unwrap());
+        assert!(!conn.use_counter(sec.id, 3).await.unwrap());
+        assert!(!conn.use_counter(sec.id, 2).await.unwrap());
+        assert!(conn.use_counter(sec.id, 4).await.unwrap());
+        let sec = conn.get_secret(Some(owner), "hotp_ident").await.unwrap();
+        assert_eq!(sec.counter, 5);
+
+        _test_cleanup(&conn).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_api_key() {
+        let conn = _test_setup().await;
         let host = "test.example.com";
         let api_key = "abc12345";
 
-        let res = conn.add_api_key(host, api_key);
+        let res = conn
+            .add_api_key(host, api_key, None, &[Scope::Verify, Scope::Qr], None)
+            .await;
         assert!(res.is_ok());
 
-        assert!(conn.api_key_exists(api_key));
+        assert!(conn.api_key_exists(api_key).await);
+        assert!(!conn.api_key_exists("abc12346").await);
+
+        // Only the hash should be stored
+        let row = conn
+            .client()
+            .await
+            .unwrap()
+            .query_one("SELECT api_key, key_hash FROM loc_auth", &[])
+            .await
+            .unwrap();
+        let raw: Option<String> = row.get("api_key");
+        let hash: String = row.get("key_hash");
+        assert!(raw.is_none());
+        assert_ne!(hash, api_key);
 
-        let res = conn.get_host_for_api_key(api_key);
+        let res = conn.get_api_key(api_key).await;
 
         assert!(res.is_ok());
-        assert_eq!(res.unwrap(), host.to_string());
+        let key = res.unwrap();
+        assert_eq!(key.host, host.to_string());
+        assert_eq!(key.scopes, vec![Scope::Verify, Scope::Qr]);
+        assert!(key.has_scope(Scope::Verify));
+        assert!(!key.has_scope(Scope::Create));
+        assert!(!key.is_admin());
+
+        // A key with its own rate limit
+        let limit = RateLimit {
+            rate: 2.5,
+            burst: 5,
+        };
+        conn.add_api_key(host, "def67890", Some(&limit), &[Scope::Admin], None)
+            .await
+            .unwrap();
+        let key = conn.get_api_key("def67890").await.unwrap();
+        assert_eq!(key.rate_limit, Some(limit));
+        assert!(key.is_admin());
+        assert!(key.has_scope(Scope::Delete));
+        assert!(conn
+            .get_api_key(api_key)
+            .await
+            .unwrap()
+            .rate_limit
+            .is_none());
+
+        _test_cleanup(&conn).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the test postgres server"]
+    async fn test_api_key_lifecycle() {
+        let conn = _test_setup().await;
+        let now = chrono::Utc::now().timestamp();
+
+        let id = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Verify], None)
+            .await
+            .unwrap();
+        let expired = conn
+            .add_api_key("old.example.com", "def67890", None, &[], Some(now - 1))
+            .await
+            .unwrap();
+
+        let key = conn.get_api_key("abc12345").await.unwrap();
+        assert!(key.is_active(now));
+        assert!(key.created_at >= now);
+        assert!(key.last_used_at.is_none());
+        assert!(!conn.get_api_key("def67890").await.unwrap().is_active(now));
+
+        conn.touch_api_key(id).await.unwrap();
+        assert!(conn
+            .get_api_key_by_id(id)
+            .await
+            .unwrap()
+            .last_used_at
+            .is_some());
+
+        let keys = conn.list_api_keys().await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].id, expired);
+
+        // Rotating replaces the key, but keeps the row
+        assert!(conn.rotate_api_key(id, "ghi12345").await.unwrap());
+        assert!(conn.get_api_key("abc12345").await.is_err());
+        assert_eq!(conn.get_api_key("ghi12345").await.unwrap().id, id);
+
+        assert!(conn.set_api_key_expiry(expired, None).await.unwrap());
+        assert!(conn.get_api_key("def67890").await.unwrap().is_active(now));
+        assert!(conn.set_api_key_expiry(expired, Some(now)).await.unwrap());
+        assert!(!conn.get_api_key("def67890").await.unwrap().is_active(now));
+        assert!(!conn.set_api_key_expiry(-1, None).await.unwrap());
+
+        assert!(conn.revoke_api_key(id).await.unwrap());
+        assert!(!conn.get_api_key("ghi12345").await.unwrap().is_active(now));
+        assert!(!conn.rotate_api_key(id, "jkl12345").await.unwrap());
+        assert!(!conn.revoke_api_key(-1).await.unwrap());
 
-        _test_cleanup(&mut conn);
+        _test_cleanup(&conn).await;
     }
 }
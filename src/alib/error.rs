@@ -1,23 +1,247 @@
-use std::error::Error;
+use super::store::{is_unavailable, AmbiguousIdent, DbUnavailable, DuplicateIdent, UnknownIdent};
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use json::object;
 use std::fmt;
+use std::sync::LockResult;
 
+/// The machine readable error codes sent to clients.  These are part of the
+/// api, so an existing code must never be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request body isn't valid JSON
+    InvalidBody,
+    /// A required request parameter is missing or has the wrong type
+    MissingParam,
+    /// A request parameter has a value that isn't allowed
+    InvalidParam,
+    /// The ident doesn't exist, or belongs to another api key
+    UnknownIdent,
+    /// The ident already exists for the api key
+    DuplicateIdent,
+    /// The db can't be reached, so the request can be retried later
+    DbUnavailable,
+    /// The client couldn't be authenticated
+    Unauthorized,
+    /// The api key doesn't have the scope for the route
+    Forbidden,
+    /// The api key is over its rate limit
+    RateLimited,
+    /// Anything else that went wrong on the server
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            Self::InvalidBody => "invalid_body",
+            Self::MissingParam => "missing_param",
+            Self::InvalidParam => "invalid_param",
+            Self::UnknownIdent => "unknown_ident",
+            Self::DuplicateIdent => "duplicate_ident",
+            Self::DbUnavailable => "db_unavailable",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::RateLimited => "rate_limited",
+            Self::Internal => "internal",
+        };
+    }
+
+    /// The HTTP status the error is sent with
+    pub fn status(&self) -> StatusCode {
+        return match self {
+            Self::InvalidBody | Self::MissingParam | Self::InvalidParam => StatusCode::BAD_REQUEST,
+            Self::UnknownIdent => StatusCode::NOT_FOUND,
+            Self::DuplicateIdent => StatusCode::CONFLICT,
+            Self::DbUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
+    }
+}
+
+/// An error response from the api.  Every error is sent as JSON like:
+/// ```
+/// {
+///     "status": false,
+///     "error": "unknown_ident",
+///     "message": "Unknown ident"
+/// }
+/// ```
 #[derive(Debug)]
-pub struct InvalidReqBody {
-    error: String,
+pub struct ApiError {
+    pub code: ErrorCode,
+    /// A human readable description, which clients shouldn't match on
+    pub message: String,
+    /// The seconds until the request can be retried, sent as `Retry-After`
+    pub retry_after: Option<u64>,
 }
 
-impl InvalidReqBody {
-    pub fn new(error: &str) -> Self {
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
         return Self {
-            error: error.to_string(),
+            code,
+            message: message.into(),
+            retry_after: None,
         };
     }
+
+    pub fn invalid_body() -> Self {
+        return Self::new(ErrorCode::InvalidBody, "Invalid JSON request body");
+    }
+
+    pub fn missing_param(name: &str) -> Self {
+        return Self::new(
+            ErrorCode::MissingParam,
+            format!("Missing the '{}' parameter", name),
+        );
+    }
+
+    pub fn unknown_ident() -> Self {
+        return Self::new(ErrorCode::UnknownIdent, "Unknown ident");
+    }
+
+    pub fn unauthorized(message: &str) -> Self {
+        return Self::new(ErrorCode::Unauthorized, message);
+    }
+
+    pub fn internal(message: &str) -> Self {
+        return Self::new(ErrorCode::Internal, message);
+    }
+
+    pub fn rate_limited(retry_after: u64) -> Self {
+        return Self {
+            retry_after: Some(retry_after),
+            ..Self::new(ErrorCode::RateLimited, "Rate limit exceeded")
+        };
+    }
+
+    /// Log an error from the store and convert it to the error for the
+    /// client.  The details are only logged, never sent.
+    pub fn from_db(context: &str, e: anyhow::Error) -> Self {
+        if is_unavailable(&e) {
+            let err = e
+                .downcast::<DbUnavailable>()
+                .unwrap_or_else(|e| DbUnavailable(e.to_string()));
+            error!("{}: {}", context, err);
+
+            return Self::new(ErrorCode::DbUnavailable, "Database unavailable");
+        } else if e.downcast_ref::<UnknownIdent>().is_some() {
+            return Self::unknown_ident();
+        } else if e.downcast_ref::<DuplicateIdent>().is_some() {
+            return Self::new(ErrorCode::DuplicateIdent, "Duplicate ident");
+        } else if e.downcast_ref::<AmbiguousIdent>().is_some() {
+            return Self::new(
+                ErrorCode::InvalidParam,
+                "The ident exists for multiple owners, so the 'owner' parameter is required",
+            );
+        }
+
+        error!("{}: {}", context, e);
+
+        return Self::internal("Database error");
+    }
 }
 
-impl Error for InvalidReqBody {}
-unsafe impl Send for InvalidReqBody {}
-impl fmt::Display for InvalidReqBody {
+impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        return write!(f, "{}", &self.error);
+        return write!(f, "{}: {}", self.code, self.message);
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = object! {
+            status: false,
+            error: self.code.as_str(),
+            message: self.message,
+        };
+        let mut resp = (
+            self.code.status(),
+            [(header::CONTENT_TYPE, "application/json")],
+            body.dump(),
+        )
+            .into_response();
+
+        if let Some(secs) = self.retry_after {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        return resp;
+    }
+}
+
+/// Take the guard from a lock, even if a panic while it was held poisoned
+/// it.  This is only for locks whose data a panic can't leave inconsistent,
+/// so each caller needs to say why that holds for its lock.
+pub fn recover_lock<G>(res: LockResult<G>) -> G {
+    return res.unwrap_or_else(|e| {
+        warn!("Recovered a lock that was poisoned by a panic");
+        e.into_inner()
+    });
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_from_db() {
+        let code = |e: anyhow::Error| ApiError::from_db("test", e).code;
+
+        assert_eq!(
+            code(DbUnavailable("down".to_string()).into()),
+            ErrorCode::DbUnavailable
+        );
+        assert_eq!(code(UnknownIdent.into()), ErrorCode::UnknownIdent);
+        assert_eq!(code(DuplicateIdent.into()), ErrorCode::DuplicateIdent);
+        assert_eq!(code(AmbiguousIdent.into()), ErrorCode::InvalidParam);
+        assert_eq!(code(anyhow!("syntax error")), ErrorCode::Internal);
+        assert_eq!(
+            ApiError::from_db("test", anyhow!("syntax error")).message,
+            "Database error"
+        );
+    }
+
+    #[test]
+    fn test_recover_lock() {
+        let lock = std::sync::Arc::new(std::sync::Mutex::new(1));
+        let l = lock.clone();
+        let res = std::thread::spawn(move || {
+            let _guard = l.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(lock.is_poisoned());
+
+        *recover_lock(lock.lock()) += 1;
+        assert_eq!(*recover_lock(lock.lock()), 2);
+    }
+
+    #[test]
+    fn test_response() {
+        let resp = ApiError::rate_limited(3).into_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "3");
+
+        let resp = ApiError::missing_param("ident").into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(resp.headers().get(header::RETRY_AFTER).is_none());
     }
 }
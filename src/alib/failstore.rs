@@ -0,0 +1,215 @@
+use super::crypto::HashedCode;
+use super::memstore::MemStore;
+use super::ratelimit::RateLimit;
+use super::scope::Scope;
+use super::store::{
+    ApiKey, ApiKeyStore, DbUnavailable, MigrationStore, RecoveryCode, Secret, SecretStore,
+};
+use super::totp::{OtpType, TotpParams};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// The failures a `FailStore` can inject
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The db can't be reached
+    Unavailable,
+    /// Any other db error, like a failed query
+    Error,
+    /// The store panics
+    Panic,
+    /// The wrapped `MemStore` panics while holding its lock, poisoning it
+    Poison,
+}
+
+/// A store for tests that wraps a `MemStore` and makes the secret methods
+/// fail on demand.  The api key methods always work, so requests still get
+/// past authentication to the handlers.
+#[derive(Default)]
+pub struct FailStore {
+    inner: MemStore,
+    failure: Mutex<Option<Failure>>,
+}
+
+impl FailStore {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Make every secret method fail, until this is called with `None`
+    pub fn fail_with(&self, failure: Option<Failure>) {
+        *self.failure.lock().unwrap() = failure;
+    }
+
+    /// Check whether the wrapped store's lock has been poisoned
+    pub fn is_poisoned(&self) -> bool {
+        return self.inner.is_poisoned();
+    }
+
+    fn check(&self) -> Result<()> {
+        let failure = *self.failure.lock().unwrap();
+
+        return match failure {
+            None => Ok(()),
+            Some(Failure::Unavailable) => {
+                Err(DbUnavailable("connection refused".to_string()).into())
+            }
+            Some(Failure::Error) => Err(anyhow!("syntax error at or near \"secrets\"")),
+            Some(Failure::Panic) => panic!("injected store panic"),
+            Some(Failure::Poison) => {
+                self.inner.panic_while_locked();
+                Ok(())
+            }
+        };
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for FailStore {
+    async fn add_api_key(
+        &self,
+        host: &str,
+        api_key: &str,
+        rate_limit: Option<&RateLimit>,
+        scopes: &[Scope],
+        expires_at: Option<i64>,
+    ) -> Result<i64> {
+        return self
+            .inner
+            .add_api_key(host, api_key, rate_limit, scopes, expires_at)
+            .await;
+    }
+
+    async fn get_api_key(&self, api_key: &str) -> Result<ApiKey> {
+        return self.inner.get_api_key(api_key).await;
+    }
+
+    async fn get_api_key_by_id(&self, id: i64) -> Result<ApiKey> {
+        return self.inner.get_api_key_by_id(id).await;
+    }
+
+    async fn get_api_key_by_cert(&self, names: &[String]) -> Result<ApiKey> {
+        return self.inner.get_api_key_by_cert(names).await;
+    }
+
+    async fn set_api_key_cert(&self, id: i64, name: Option<&str>) -> Result<bool> {
+        return self.inner.set_api_key_cert(id, name).await;
+    }
+
+    async fn set_api_key_expiry(&self, id: i64, expires_at: Option<i64>) -> Result<bool> {
+        return self.inner.set_api_key_expiry(id, expires_at).await;
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        return self.inner.list_api_keys().await;
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        return self.inner.revoke_api_key(id).await;
+    }
+
+    async fn rotate_api_key(&self, id: i64, api_key: &str) -> Result<bool> {
+        return self.inner.rotate_api_key(id, api_key).await;
+    }
+
+    async fn touch_api_key(&self, id: i64) -> Result<()> {
+        return self.inner.touch_api_key(id).await;
+    }
+}
+
+#[async_trait]
+impl SecretStore for FailStore {
+    async fn create_secret(
+        &self,
+        owner: i64,
+        ident: &str,
+        secret: &str,
+        params: &TotpParams,
+        kind: OtpType,
+    ) -> Result<()> {
+        self.check()?;
+        return self
+            .inner
+            .create_secret(owner, ident, secret, params, kind)
+            .await;
+    }
+
+    async fn delete_secret(&self, owner: Option<i64>, ident: &str) -> Result<()> {
+        self.check()?;
+        return self.inner.delete_secret(owner, ident).await;
+    }
+
+    async fn get_secret(&self, owner: Option<i64>, ident: &str) -> Result<Secret> {
+        self.check()?;
+        return self.inner.get_secret(owner, ident).await;
+    }
+
+    async fn get_secret_by_id(&self, id: i64) -> Result<(String, String)> {
+        self.check()?;
+        return self.inner.get_secret_by_id(id).await;
+    }
+
+    async fn assign_unowned_secrets(&self, owner: i64) -> Result<u64> {
+        self.check()?;
+        return self.inner.assign_unowned_secrets(owner).await;
+    }
+
+    async fn use_step(&self, id: i64, step: i64) -> Result<bool> {
+        self.check()?;
+        return self.inner.use_step(id, step).await;
+    }
+
+    async fn use_counter(&self, id: i64, counter: i64) -> Result<bool> {
+        self.check()?;
+        return self.inner.use_counter(id, counter).await;
+    }
+
+    async fn set_recovery_codes(&self, id: i64, codes: &[HashedCode]) -> Result<()> {
+        self.check()?;
+        return self.inner.set_recovery_codes(id, codes).await;
+    }
+
+    async fn get_recovery_codes(&self, id: i64) -> Result<Vec<RecoveryCode>> {
+        self.check()?;
+        return self.inner.get_recovery_codes(id).await;
+    }
+
+    async fn use_recovery_code(&self, code_id: i64) -> Result<bool> {
+        self.check()?;
+        return self.inner.use_recovery_code(code_id).await;
+    }
+
+    async fn get_locked_until(&self, id: i64) -> Result<Option<i64>> {
+        self.check()?;
+        return self.inner.get_locked_until(id).await;
+    }
+
+    async fn record_verify_failure(&self, id: i64) -> Result<i32> {
+        self.check()?;
+        return self.inner.record_verify_failure(id).await;
+    }
+
+    async fn lock_ident(&self, id: i64, until: i64) -> Result<()> {
+        self.check()?;
+        return self.inner.lock_ident(id, until).await;
+    }
+
+    async fn reset_verify_failures(&self, id: i64) -> Result<()> {
+        self.check()?;
+        return self.inner.reset_verify_failures(id).await;
+    }
+
+    async fn count_stale_secrets(&self) -> Result<i64> {
+        self.check()?;
+        return self.inner.count_stale_secrets().await;
+    }
+
+    async fn rotate_secrets_batch(&self, batch_size: i64) -> Result<u64> {
+        self.check()?;
+        return self.inner.rotate_secrets_batch(batch_size).await;
+    }
+}
+
+#[async_trait]
+impl MigrationStore for FailStore {}
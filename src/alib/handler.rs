@@ -1,114 +1,299 @@
-use super::{db::DB, error::InvalidReqBody};
-use anyhow::Result;
-use bodyparser::Json;
+use super::{
+    crypto::{api_key_id, hash_recovery_code, verify_recovery_code},
+    error::{ApiError, ErrorCode},
+    lockout::LockoutPolicy,
+    ratelimit::RateLimiter,
+    recovery::{gen_code, normalize, RecoveryPolicy},
+    scope::Scope,
+    server::Peer,
+    store::{is_unavailable, ApiKey, Secret, Store, UnknownApiKey},
+    tls::ClientAuth,
+    totp::{
+        gen_secret, matching_counter, matching_step, otpauth_url, resync_counter, OtpType,
+        TotpLimits, VerifyWindow,
+    },
+};
+use anyhow::{anyhow, Result};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{from_fn_with_state, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Extension, Router,
+};
 use configparser::ini::Ini;
-use google_authenticator::{ErrorCorrectionLevel::Medium, GoogleAuthenticator};
-use iron::{error, mime, prelude::*, status, Handler};
-use json::object;
-use postgres::error::DbError;
-use router::Router;
-use std::sync::{Arc, Mutex};
+use json::{object, JsonValue};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::{render::svg, EcLevel, QrCode};
+use std::any::Any;
+use std::sync::Arc;
+use tower_http::catch_panic::CatchPanicLayer;
 
-// shortcut type
-type Callback = Box<dyn Fn(&mut Request, Arc<Ini>, Arc<Mutex<DB>>) -> IronResult<Response>>;
+/// The largest request body that will be read
+const MAX_BODY_SIZE: usize = 64 * 1024;
 
-pub struct AuthHandler {
+/// Every error is sent as an `ApiError`, so the handlers can return early
+/// with `?`
+type HandlerResult = Result<Response, ApiError>;
+
+/// Everything the handlers share
+#[derive(Clone)]
+struct App {
     config: Arc<Ini>,
-    db: Arc<Mutex<DB>>,
-    func: Callback, // Callback func
+    db: Arc<dyn Store>,
+    limiter: Arc<RateLimiter>,
+    client_auth: ClientAuth,
+    totp: Arc<TotpLimits>,
+    lockout: LockoutPolicy,
+    window: VerifyWindow,
 }
 
-impl AuthHandler {
-    fn new(
-        config: Arc<Ini>,
-        db: Arc<Mutex<DB>>,
-        func: Callback, // Callback func
-    ) -> Self {
-        return Self { config, db, func };
+/// The JSON request body, parsed by `require_auth()` for the handlers.  It's
+/// `None` if the request didn't have a body.
+#[derive(Clone)]
+struct ReqBody(Option<JsonValue>);
+
+impl App {
+    /// Find the api key for the request, from the api key passed in and/or
+    /// the client certificate, depending on `[auth] client_auth`
+    async fn authenticate(&self, peer: &Peer, api_key: Option<&str>) -> Result<ApiKey, ApiError> {
+        let names = if self.client_auth.uses_cert() {
+            peer.cert_names.as_deref()
+        } else {
+            None
+        };
+
+        return match self.client_auth {
+            ClientAuth::ApiKey => self.by_api_key(api_key).await,
+            ClientAuth::Cert => self.by_cert(names).await,
+            ClientAuth::Either => match self.by_cert(names).await {
+                Ok(k) => Ok(k),
+                Err(e) if e.code == ErrorCode::DbUnavailable => Err(e),
+                Err(_) => self.by_api_key(api_key).await,
+            },
+            ClientAuth::Both => {
+                let key = self.by_cert(names).await?;
+                if self.by_api_key(api_key).await?.id != key.id {
+                    error!(
+                        "The api_key doesn't match the client certificate for {}",
+                        key.host
+                    );
+                    return Err(ApiError::unauthorized("Invalid api key"));
+                }
+                Ok(key)
+            }
+        };
     }
-}
 
-impl Handler for AuthHandler {
-    fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        /*
-        let r = req.extensions.get::<Router>().unwrap();
-        let api_key = r.find(API_KEY);
-        */
-        let body = match req.get::<Json>() {
-            Ok(Some(b)) => b,
-            _ => {
-                error!("Unable to parse request body");
-                return Err(IronError::new(
-                    InvalidReqBody::new("Invalid JSON body"),
-                    (status::BadRequest, "Invalid JSON request body"),
-                ));
+    async fn by_api_key(&self, api_key: Option<&str>) -> Result<ApiKey, ApiError> {
+        let api_key = match api_key {
+            Some(k) => k,
+            None => return Err(ApiError::unauthorized("Missing the api key")),
+        };
+
+        // Only ever log the lookup id, never the full key
+        debug!("API KEY ID: {:?}", api_key_id(api_key));
+        return match self.db.get_api_key(api_key).await {
+            Ok(k) if k.is_active(chrono::Utc::now().timestamp()) => Ok(k),
+            Ok(k) => {
+                error!("Revoked or expired api_key passed in for {}", k.host);
+                Err(ApiError::unauthorized("Invalid api key"))
+            }
+            Err(e) if is_unavailable(&e) => Err(ApiError::from_db("Error getting the api key", e)),
+            Err(_) => {
+                error!("Invalid api_key passed in: {}...", api_key_id(api_key));
+                Err(ApiError::unauthorized("Invalid api key"))
             }
         };
+    }
 
-        let api_key = body["api_key"].as_str();
-        validate_params(&[api_key])?;
-        let api_key = api_key.unwrap();
-        let host: Result<String>;
-
-        debug!("API KEY: {:?}", api_key);
-        {
-            // I need a mutable reference to the database for operations
-            let mut mdb = self.db.lock().unwrap();
-            host = mdb.get_host_for_api_key(api_key);
-
-            if host.is_err() {
-                error!("Invalid api_key passed in: {}", api_key);
-                return Err(IronError::new(
-                    InvalidReqBody::new("Invalid api key"),
-                    (status::BadRequest, "Invalid api key"),
-                ));
+    async fn by_cert(&self, names: Option<&[String]>) -> Result<ApiKey, ApiError> {
+        let names = match names {
+            Some(n) => n,
+            None => {
+                error!("No client certificate was presented");
+                return Err(ApiError::unauthorized("Client certificate required"));
             }
-        }
+        };
 
-        info!("Validated the API key for {}", host.unwrap());
-        return (*self.func)(req, self.config.clone(), self.db.clone());
+        return match self.db.get_api_key_by_cert(names).await {
+            Ok(k) if k.is_active(chrono::Utc::now().timestamp()) => Ok(k),
+            Ok(k) => {
+                error!("Client certificate for revoked or expired key {}", k.host);
+                Err(ApiError::unauthorized("Invalid client certificate"))
+            }
+            Err(e) if is_unavailable(&e) => Err(ApiError::from_db("Error getting the api key", e)),
+            Err(_) => {
+                error!("No api key for the client certificate: {:?}", names);
+                Err(ApiError::unauthorized("Invalid client certificate"))
+            }
+        };
     }
 }
 
-unsafe impl Send for AuthHandler {}
-unsafe impl Sync for AuthHandler {}
+/// The middleware in front of every route but the index.  It authenticates
+/// the client, checks that the key has the route's scope and applies the
+/// rate limit, then hands the key and the parsed body on to the handler.
+async fn require_auth(
+    State((app, scope)): State<(App, Scope)>,
+    req: Request,
+    next: Next,
+) -> HandlerResult {
+    let (mut parts, body) = req.into_parts();
 
-pub fn get_router_w_routes(conf: Ini, db: DB) -> Result<Router> {
-    let mut router = Router::new();
-    let conf = Arc::new(conf);
-    let db = Arc::new(Mutex::new(db));
+    // The body is optional here, since the api key can be in a header
+    let body = match to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(b) if b.iter().all(u8::is_ascii_whitespace) => None,
+        Ok(b) => match std::str::from_utf8(&b).map(json::parse) {
+            Ok(Ok(j)) => Some(j),
+            _ => return Err(ApiError::invalid_body()),
+        },
+        Err(_) => return Err(ApiError::invalid_body()),
+    };
+    let body_key = body
+        .as_ref()
+        .and_then(|b| b["api_key"].as_str())
+        .map(str::to_string);
+
+    let allow_body_key = app
+        .config
+        .getbool("auth", "allow_body_api_key")
+        .unwrap()
+        .unwrap_or(true);
+    let api_key = match header_api_key(&parts.headers) {
+        Some(k) => Some(k),
+        None if body_key.is_some() && !allow_body_key => {
+            warn!("Rejected an api_key in the request body");
+            return Err(ApiError::unauthorized(
+                "The api_key must be sent in the Authorization header",
+            ));
+        }
+        None => body_key,
+    };
 
-    router.get("/", index_page, "index");
+    let peer = parts.extensions.get::<Peer>().cloned().unwrap_or_default();
+    let key = app.authenticate(&peer, api_key.as_deref()).await?;
 
-    router.post(
-        "/create",
-        AuthHandler::new(conf.clone(), db.clone(), Box::new(create)),
-        "create",
-    );
+    // Rate limit before anything else is done with the key, so a limited
+    // client can't keep the db busy.  Unix socket clients don't have an
+    // address, but they're all local
+    let ip = peer
+        .addr
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|| "local".to_string());
+    let bucket = app.limiter.bucket_key(key.id, &ip);
+    if let Err(secs) = app.limiter.check(&bucket, key.rate_limit.as_ref()) {
+        warn!("Rate limit exceeded for {} ({})", key.host, bucket);
+        return Err(ApiError::rate_limited(secs));
+    }
 
-    router.post(
-        "/delete",
-        AuthHandler::new(conf.clone(), db.clone(), Box::new(delete)),
-        "delete",
-    );
+    if let Err(e) = app.db.touch_api_key(key.id).await {
+        // Not worth failing the request over
+        warn!(
+            "Failed to update the last used time for {}: {}",
+            key.host, e
+        );
+    }
 
-    router.post(
-        "/verify",
-        AuthHandler::new(conf.clone(), db.clone(), Box::new(verify)),
-        "verify",
-    );
+    info!("Validated the API key for {}", key.host);
 
-    router.post(
-        "/qr",
-        AuthHandler::new(conf.clone(), db.clone(), Box::new(qr)),
-        "qr",
-    );
+    if !key.has_scope(scope) {
+        warn!("API key for {} is missing the {} scope", key.host, scope);
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!("API key is missing the '{}' scope", scope),
+        ));
+    }
 
-    router.post(
-        "/qr_url",
-        AuthHandler::new(conf.clone(), db.clone(), Box::new(qr_url)),
-        "qr_url",
-    );
+    parts.extensions.insert(key);
+    parts.extensions.insert(ReqBody(body));
+
+    return Ok(next.run(Request::from_parts(parts, Body::empty())).await);
+}
+
+/// Build the router with all the routes, backed by the given store.  The
+/// server adds a `Peer` to each request, which is where client certificates
+/// are found when they're used for authentication.
+pub fn get_router_w_routes(conf: Ini, db: Arc<dyn Store>) -> Result<Router> {
+    let conf = Arc::new(conf);
+    let app = App {
+        limiter: Arc::new(RateLimiter::from_config(&conf)),
+        client_auth: ClientAuth::from_config(&conf)?,
+        totp: Arc::new(TotpLimits::from_config(&conf)?),
+        lockout: LockoutPolicy::from_config(&conf)?,
+        window: VerifyWindow::from_config(&conf)?,
+        config: conf,
+        db,
+    };
+    // These are read for every request, so make sure they're valid up front
+    app.config
+        .getbool("auth", "allow_body_api_key")
+        .map_err(|e| anyhow!(e))?;
+    for (section, name) in [
+        ("hotp", "look_ahead"),
+        ("hotp", "resync_window"),
+        ("recovery", "count"),
+        ("recovery", "max_count"),
+    ] {
+        app.config.getuint(section, name).map_err(|e| anyhow!(e))?;
+    }
+
+    let router = Router::new()
+        .route("/", get(index_page))
+        .route(
+            "/create",
+            post(create).route_layer(from_fn_with_state(
+                (app.clone(), Scope::Create),
+                require_auth,
+            )),
+        )
+        .route(
+            "/delete",
+            post(delete).route_layer(from_fn_with_state(
+                (app.clone(), Scope::Delete),
+                require_auth,
+            )),
+        )
+        .route(
+            "/verify",
+            post(verify).route_layer(from_fn_with_state(
+                (app.clone(), Scope::Verify),
+                require_auth,
+            )),
+        )
+        .route(
+            "/resync",
+            post(resync).route_layer(from_fn_with_state(
+                (app.clone(), Scope::Verify),
+                require_auth,
+            )),
+        )
+        .route(
+            "/recovery_codes/generate",
+            post(generate_recovery_codes).route_layer(from_fn_with_state(
+                (app.clone(), Scope::Create),
+                require_auth,
+            )),
+        )
+        .route(
+            "/recovery_codes/verify",
+            post(verify_recovery).route_layer(from_fn_with_state(
+                (app.clone(), Scope::Verify),
+                require_auth,
+            )),
+        )
+        .route(
+            "/qr",
+            post(qr).route_layer(from_fn_with_state((app.clone(), Scope::Qr), require_auth)),
+        )
+        .route(
+            "/qr_url",
+            post(qr_url).route_layer(from_fn_with_state((app.clone(), Scope::Qr), require_auth)),
+        )
+        .with_state(app)
+        .layer(CatchPanicLayer::custom(panic_response));
 
     return Ok(router);
 }
@@ -122,75 +307,101 @@ pub fn get_router_w_routes(conf: Ini, db: DB) -> Result<Router> {
 /// ```
 /// {
 ///    "api_key": "abc123",
-///    "ident": "key identifier"
+///    "ident": "key identifier",
+///    "digits": 6,  // Optional
+///    "period": 30,  // Optional
+///    "algorithm": "SHA1",  // Optional, or "SHA256" or "SHA512"
+///    "type": "totp",  // Optional, or "hotp"
+///    "owner": 1  // Optional, admin keys only
 /// }
 /// ```
 ///
+/// The ident is owned by the api key that created it, and only that key can
+/// use it afterwards.  An admin key can create an ident for another key by
+/// passing that key's id as the `owner`, which fails with "invalid_param" if
+/// there is no such key.
+///
+/// The `digits`, `period` and `algorithm` default to the `[totp]` config,
+/// which also limits what can be asked for.  An "hotp" ident is for counter
+/// based tokens, which start at counter 0 and ignore the `period`.
+///
 /// The response will be:
 /// ```
 /// {
 ///    "status": true,
 ///    "ident": <ident>,
-///    "secret": <secret>
+///    "secret": <secret>,
+///    "digits": 6,
+///    "period": 30,
+///    "algorithm": "SHA1",
+///    "type": "totp"
 /// }
 /// ```
-fn create(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Response> {
-    let g = GoogleAuthenticator::new();
-    let body = match req.get::<Json>() {
-        Ok(Some(b)) => b,
-        _ => {
-            error!("Unable to parse request body");
-            return Err(IronError::new(
-                InvalidReqBody::new("Invalid JSON body"),
-                (status::BadRequest, "Invalid JSON request body"),
-            ));
-        }
-    };
+async fn create(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let body = require_body(&body)?;
 
     let ident = body["ident"].as_str();
-    let len = conf.getuint("auth", "secret_len").unwrap().unwrap() as u8;
-    let secret = g.create_secret(len);
-
-    validate_params(&[ident])?;
-
-    // I need a mutable reference to the database for operations
-    let mut mdb = db.lock().unwrap();
-
-    if let Err(e) = mdb.create_secret(ident.unwrap(), &secret) {
-        let err_code = e
-            .root_cause()
-            .downcast_ref::<DbError>()
-            .unwrap()
-            .code()
-            .code();
-        let mut err = format!("Database error: {}", e);
-        if err_code == "23505" {
-            err = "Database error: duplicate entry".to_string();
-        }
+    let len = app.config.getuint("auth", "secret_len").unwrap().unwrap() as usize;
+    let secret = gen_secret(len);
 
-        return Ok(Response::with((
-            get_json_ct(),
-            status::Ok,
-            object! {
-                status: false,
-                message: err,
+    validate_params(&[("ident", ident)])?;
+
+    let params = match app.totp.params(
+        optional_param(body, "digits", JsonValue::as_u32)?,
+        optional_param(body, "period", JsonValue::as_u64)?,
+        optional_param(body, "algorithm", JsonValue::as_str)?,
+    ) {
+        Ok(p) => p,
+        Err(e) => return Err(ApiError::new(ErrorCode::InvalidParam, e.to_string())),
+    };
+    let kind = match optional_param(body, "type", JsonValue::as_str)? {
+        Some(t) => match t.parse::<OtpType>() {
+            Ok(k) => k,
+            Err(e) => return Err(ApiError::new(ErrorCode::InvalidParam, e.to_string())),
+        },
+        None => OtpType::Totp,
+    };
+
+    let owner = get_owner(&key, body["owner"].as_i64()).unwrap_or(key.id);
+    if owner != key.id {
+        match app.db.get_api_key_by_id(owner).await {
+            Ok(_) => (),
+            Err(e) if e.is::<UnknownApiKey>() => {
+                return Err(ApiError::new(
+                    ErrorCode::InvalidParam,
+                    format!("There is no api key with the id {} for 'owner'", owner),
+                ));
             }
-            .dump(),
-        )));
+            Err(e) => return Err(ApiError::from_db("Error looking up the owner", e)),
+        }
     }
 
-    info!("Secret added to db for {}: {:?}", ident.unwrap(), secret);
+    if let Err(e) = app
+        .db
+        .create_secret(owner, ident.unwrap(), &secret, &params, kind)
+        .await
+    {
+        return Err(ApiError::from_db("Error creating the secret", e));
+    }
+
+    info!("Secret added to db for {}", ident.unwrap());
 
-    return Ok(Response::with((
-        get_json_ct(),
-        status::Ok,
+    return Ok(json_response(
+        StatusCode::OK,
         object! {
             status: true,
             ident: ident.unwrap(),
-            secret: secret
-        }
-        .dump(),
-    )));
+            secret: secret,
+            digits: params.digits,
+            period: params.period,
+            algorithm: params.algorithm.to_string(),
+            "type": kind.to_string()
+        },
+    ));
 }
 
 /// This consists of a reuqest to delete a secret. The request body
@@ -198,7 +409,8 @@ fn create(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<R
 /// ```
 /// {
 ///    "api_key": "abc123",
-///    "ident": "key identifier"
+///    "ident": "key identifier",
+///    "owner": 1  // Optional, admin keys only
 /// }
 /// ```
 ///
@@ -208,49 +420,33 @@ fn create(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<R
 ///    "status": true,
 /// }
 /// ```
-fn delete(req: &mut Request, _conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Response> {
-    let body = match req.get::<Json>() {
-        Ok(Some(b)) => b,
-        _ => {
-            error!("Unable to parse request body");
-            return Err(IronError::new(
-                InvalidReqBody::new("Invalid JSON body"),
-                (status::BadRequest, "Invalid JSON request body"),
-            ));
-        }
-    };
+///
+/// Deleting an ident that doesn't exist fails with "unknown_ident".
+async fn delete(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let body = require_body(&body)?;
 
     let ident = body["ident"].as_str();
 
-    validate_params(&[ident])?;
+    validate_params(&[("ident", ident)])?;
 
-    // I need a mutable reference to the database for operations
-    let mut mdb = db.lock().unwrap();
+    let owner = get_owner(&key, body["owner"].as_i64());
 
-    if let Err(e) = mdb.delete_secret(ident.unwrap()) {
-        let err = e.root_cause().downcast_ref::<DbError>().unwrap().message();
-
-        return Ok(Response::with((
-            get_json_ct(),
-            status::Ok,
-            object! {
-                status: false,
-                message: err,
-            }
-            .dump(),
-        )));
+    if let Err(e) = app.db.delete_secret(owner, ident.unwrap()).await {
+        return Err(ApiError::from_db("Error deleting the secret", e));
     }
 
     info!("Secret deleted for ident: {:?}", ident.unwrap());
 
-    return Ok(Response::with((
-        get_json_ct(),
-        status::Ok,
+    return Ok(json_response(
+        StatusCode::OK,
         object! {
             status: true,
-        }
-        .dump(),
-    )));
+        },
+    ));
 }
 
 /// This will verify that a code is valid for the given identity (hostname)
@@ -259,7 +455,9 @@ fn delete(req: &mut Request, _conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<
 /// {
 ///     "api_key": "abc123",
 ///     "ident": "key identifier",
-///     "code": 123456
+///     "code": 123456,
+///     "window": 1,  // Optional
+///     "owner": 1  // Optional, admin keys only
 /// }
 /// ```
 ///
@@ -267,49 +465,356 @@ fn delete(req: &mut Request, _conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<
 /// ```
 /// {
 ///     "status": true|false,
-///     "verified": true|false
+///     "verified": true|false,
+///     "offset": 0,  // The matched step offset, when a code matched
+///     "reason": "invalid_code"|"replayed"|"locked",  // Only when verified is false
+///     "locked_until": 1700000000  // Only when the ident is locked
 /// }
 /// ```
-fn verify(req: &mut Request, _conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Response> {
-    let g = GoogleAuthenticator::new();
-    let body = match req.get::<Json>() {
-        Ok(Some(b)) => b,
-        _ => {
-            error!("Unable to parse request body");
-            return Err(IronError::new(
-                InvalidReqBody::new("Invalid JSON body"),
-                (status::BadRequest, "Invalid JSON request body"),
-            ));
-        }
+///
+/// After `[lockout] max_failures` consecutive invalid codes, the ident is
+/// locked and every request fails until `locked_until` (a unix timestamp).
+/// Each further failure doubles the lock time up to `[lockout] max_secs`,
+/// and a successful verification resets the count.
+///
+/// The code must have the ident's number of digits.  For a TOTP ident, the
+/// `window` is the number of time steps, of the ident's period, on either
+/// side of the current one that are also accepted to allow for clock drift.
+/// It defaults to `[auth] verify_window` and is capped at
+/// `[auth] max_verify_window`.
+///
+/// An HOTP ident accepts the code for its next counter or up to
+/// `[hotp] look_ahead` counters after it, since the token moves on every
+/// time a code is generated.  The `window` is ignored, and the `offset` is
+/// how far ahead the token was.
+///
+/// A code is only ever accepted once, per RFC 6238 section 5.2.  Sending the
+/// same code again, or a code from an earlier time step than the last
+/// accepted one, will fail with a reason of "replayed".  An HOTP code that
+/// was already used is behind the counter, so it fails as "invalid_code".
+async fn verify(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let body = require_body(&body)?;
+    let db = &app.db;
+
+    let ident = body["ident"].as_str();
+    let code = body["code"].as_str();
+
+    validate_params(&[("ident", ident), ("code", code)])?;
+
+    let window = app.window.get(body["window"].as_u64());
+    let owner = get_owner(&key, body["owner"].as_i64());
+
+    let sec = get_secret(ident.unwrap(), owner, db.as_ref()).await?;
+    let now = chrono::Utc::now().timestamp();
+
+    if let Some(resp) = check_locked(&app, &sec, ident.unwrap(), now, "verified").await? {
+        return Ok(resp);
+    }
+
+    let matched = match sec.kind {
+        OtpType::Totp => matching_step(&sec.secret, &sec.params, code.unwrap(), window)
+            .map(|(step, offset)| (step as i64, offset)),
+        OtpType::Hotp => matching_counter(
+            &sec.secret,
+            &sec.params,
+            code.unwrap(),
+            sec.counter as u64,
+            get_hotp_setting(&app.config, "look_ahead"),
+        )
+        .map(|c| (c as i64, c as i64 - sec.counter)),
     };
 
+    let (step, offset) = match matched {
+        Some(m) => m,
+        None => return verify_failed(&app, &sec, ident.unwrap(), now, "verified").await,
+    };
+
+    let res = match sec.kind {
+        OtpType::Totp => db.use_step(sec.id, step).await,
+        OtpType::Hotp => db.use_counter(sec.id, step).await,
+    };
+    let ret = match res {
+        Ok(r) => r,
+        Err(e) => return Err(ApiError::from_db("Error recording the used code", e)),
+    };
+
+    if !ret {
+        warn!("Replayed code for ident: {:?}", ident.unwrap());
+        return Ok(json_response(
+            StatusCode::OK,
+            object! {status: true, verified: false, offset: offset, reason: "replayed"},
+        ));
+    }
+
+    if let Err(e) = db.reset_verify_failures(sec.id).await {
+        return Err(ApiError::from_db("Error resetting the failures", e));
+    }
+
+    if offset != 0 {
+        info!("Code for {:?} matched at offset {}", ident.unwrap(), offset);
+    }
+
+    return Ok(json_response(
+        StatusCode::OK,
+        object! {status: true, verified: true, offset: offset},
+    ));
+}
+
+/// This will resync the counter of an HOTP ident, for a token that has been
+/// used so much without verifying that it's past the look ahead window of
+/// `/verify`.  The request body should look like:
+/// ```
+/// {
+///     "api_key": "abc123",
+///     "ident": "key identifier",
+///     "code": 123456,
+///     "next_code": 654321,
+///     "owner": 1  // Optional, admin keys only
+/// }
+/// ```
+///
+/// The response will be:
+/// ```
+/// {
+///     "status": true|false,
+///     "resynced": true|false,
+///     "counter": 42,  // The next counter expected, when it was resynced
+///     "reason": "invalid_code"|"replayed"|"locked",  // Only when resynced is false
+///     "locked_until": 1700000000  // Only when the ident is locked
+/// }
+/// ```
+///
+/// The `code` and `next_code` are two consecutive codes from the token,
+/// which are searched for up to `[hotp] resync_window` counters past the
+/// expected one.  Failures count towards the same lockout as `/verify`.
+async fn resync(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let body = require_body(&body)?;
+    let db = &app.db;
+
     let ident = body["ident"].as_str();
     let code = body["code"].as_str();
+    let next_code = body["next_code"].as_str();
 
-    validate_params(&[ident, code])?;
-
-    let secret = match get_secret(ident.unwrap(), db) {
-        Ok(sec) => sec,
-        Err(_) => {
-            return Ok(Response::with((
-                get_json_ct(),
-                status::Ok,
-                object! {
-                    status: false,
-                    message: "Invalid identity",
-                }
-                .dump(),
-            )));
-        }
+    validate_params(&[("ident", ident), ("code", code), ("next_code", next_code)])?;
+
+    let owner = get_owner(&key, body["owner"].as_i64());
+
+    let sec = get_secret(ident.unwrap(), owner, db.as_ref()).await?;
+    let now = chrono::Utc::now().timestamp();
+
+    if sec.kind != OtpType::Hotp {
+        return Err(ApiError::new(
+            ErrorCode::InvalidParam,
+            "Only hotp idents can be resynced",
+        ));
+    }
+
+    if let Some(resp) = check_locked(&app, &sec, ident.unwrap(), now, "resynced").await? {
+        return Ok(resp);
+    }
+
+    let counter = match resync_counter(
+        &sec.secret,
+        &sec.params,
+        code.unwrap(),
+        next_code.unwrap(),
+        sec.counter as u64,
+        get_hotp_setting(&app.config, "resync_window"),
+    ) {
+        Some(c) => c as i64,
+        None => return verify_failed(&app, &sec, ident.unwrap(), now, "resynced").await,
     };
 
-    let ret = g.verify_code(&secret, code.unwrap(), 0, 0);
+    let ret = match db.use_counter(sec.id, counter).await {
+        Ok(r) => r,
+        Err(e) => return Err(ApiError::from_db("Error recording the used code", e)),
+    };
+
+    if !ret {
+        warn!("Replayed resync codes for ident: {:?}", ident.unwrap());
+        return Ok(json_response(
+            StatusCode::OK,
+            object! {status: true, resynced: false, reason: "replayed"},
+        ));
+    }
+
+    if let Err(e) = db.reset_verify_failures(sec.id).await {
+        return Err(ApiError::from_db("Error resetting the failures", e));
+    }
+
+    info!(
+        "Resynced {:?} from counter {} to {}",
+        ident.unwrap(),
+        sec.counter,
+        counter + 1
+    );
 
-    return Ok(Response::with((
-        get_json_ct(),
-        status::Ok,
-        object! {status: true, verified: ret}.dump(),
-    )));
+    return Ok(json_response(
+        StatusCode::OK,
+        object! {status: true, resynced: true, counter: counter + 1},
+    ));
+}
+
+/// This will generate a new set of single use recovery codes for an ident,
+/// for when the user loses their authenticator.  The request body should
+/// look like:
+/// ```
+/// {
+///     "api_key": "abc123",
+///     "ident": "key identifier",
+///     "count": 10,  // Optional
+///     "owner": 1  // Optional, admin keys only
+/// }
+/// ```
+///
+/// The response will be:
+/// ```
+/// {
+///     "status": true,
+///     "ident": <ident>,
+///     "codes": ["ABCDE-FGHIJ", ...]
+/// }
+/// ```
+///
+/// The `count` defaults to `[recovery] count` and is capped at
+/// `[recovery] max_count`.  Only hashes of the codes are stored, so this is
+/// the only time they can be seen.  Any codes the ident already had stop
+/// working.
+async fn generate_recovery_codes(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let body = require_body(&body)?;
+
+    let ident = body["ident"].as_str();
+
+    validate_params(&[("ident", ident)])?;
+
+    let policy = RecoveryPolicy::from_config(&app.config);
+    let count = policy.count(optional_param(body, "count", JsonValue::as_u64)?);
+    let owner = get_owner(&key, body["owner"].as_i64());
+
+    let sec = get_secret(ident.unwrap(), owner, app.db.as_ref()).await?;
+
+    let codes: Vec<String> = (0..count).map(|_| gen_code()).collect();
+    let hashed: Vec<_> = codes
+        .iter()
+        .map(|c| hash_recovery_code(&normalize(c)))
+        .collect();
+
+    if let Err(e) = app.db.set_recovery_codes(sec.id, &hashed).await {
+        return Err(ApiError::from_db("Error storing the recovery codes", e));
+    }
+
+    info!(
+        "Generated {} recovery codes for ident: {:?}",
+        count,
+        ident.unwrap()
+    );
+
+    return Ok(json_response(
+        StatusCode::OK,
+        object! {status: true, ident: ident.unwrap(), codes: codes},
+    ));
+}
+
+/// This will verify a recovery code for an ident, using it up.  The request
+/// body should look like:
+/// ```
+/// {
+///     "api_key": "abc123",
+///     "ident": "key identifier",
+///     "code": "ABCDE-FGHIJ",
+///     "owner": 1  // Optional, admin keys only
+/// }
+/// ```
+///
+/// The response will be:
+/// ```
+/// {
+///     "status": true|false,
+///     "verified": true|false,
+///     "remaining": 9,  // The unused codes left, when the code was accepted
+///     "reason": "invalid_code"|"replayed"|"locked",  // Only when verified is false
+///     "locked_until": 1700000000  // Only when the ident is locked
+/// }
+/// ```
+///
+/// The code is matched ignoring case, spaces and dashes.  Failures count
+/// towards the same lockout as `/verify`.
+async fn verify_recovery(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let body = require_body(&body)?;
+    let db = &app.db;
+
+    let ident = body["ident"].as_str();
+    let code = body["code"].as_str();
+
+    validate_params(&[("ident", ident), ("code", code)])?;
+
+    let owner = get_owner(&key, body["owner"].as_i64());
+
+    let sec = get_secret(ident.unwrap(), owner, db.as_ref()).await?;
+    let now = chrono::Utc::now().timestamp();
+
+    if let Some(resp) = check_locked(&app, &sec, ident.unwrap(), now, "verified").await? {
+        return Ok(resp);
+    }
+
+    let codes = match db.get_recovery_codes(sec.id).await {
+        Ok(c) => c,
+        Err(e) => return Err(ApiError::from_db("Error getting the recovery codes", e)),
+    };
+
+    let code = normalize(code.unwrap());
+    let found = codes
+        .iter()
+        .find(|c| verify_recovery_code(&code, &c.hashed.salt, &c.hashed.hash));
+    let found = match found {
+        Some(c) => c,
+        None => return verify_failed(&app, &sec, ident.unwrap(), now, "verified").await,
+    };
+
+    let ret = match db.use_recovery_code(found.id).await {
+        Ok(r) => r,
+        Err(e) => return Err(ApiError::from_db("Error using the recovery code", e)),
+    };
+
+    if !ret {
+        warn!("Replayed recovery code for ident: {:?}", ident.unwrap());
+        return Ok(json_response(
+            StatusCode::OK,
+            object! {status: true, verified: false, reason: "replayed"},
+        ));
+    }
+
+    if let Err(e) = db.reset_verify_failures(sec.id).await {
+        return Err(ApiError::from_db("Error resetting the failures", e));
+    }
+
+    let remaining = codes.len() - 1;
+    info!(
+        "Recovery code used for ident {:?}, {} left",
+        ident.unwrap(),
+        remaining
+    );
+
+    return Ok(json_response(
+        StatusCode::OK,
+        object! {status: true, verified: true, remaining: remaining},
+    ));
 }
 
 /// This will create and return an SVG format and return it as a string.
@@ -319,54 +824,43 @@ fn verify(req: &mut Request, _conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<
 ///     "api_key": "abc123",
 ///     "ident": "key identifier",
 ///     "name": "name for code, could be company name",
-///     "title": "title for the code"
+///     "title": "title for the code",
+///     "owner": 1  // Optional, admin keys only
 /// }
 ///
-//// The response will look like:
+/// The response will look like:
 /// ```
 /// {
 ///     "status": true,
 ///     "qr_code": "SVG string"
 /// }
 /// ```
-fn qr(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Response> {
-    let goog = GoogleAuthenticator::new();
-    let (_, name, title, secret, width, height) = match get_qr_data(req, conf.clone(), db) {
-        Ok(t) => t,
-        Err(_) => {
-            return Ok(Response::with((
-                get_json_ct(),
-                status::Ok,
-                object! {
-                    status: false,
-                    message: "Failed to get qr data",
-                }
-                .dump(),
-            )));
-        }
-    };
+async fn qr(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let (_, name, title, sec, width, height) = get_qr_data(&app, &key, &body).await?;
+    let url = otpauth_url(&sec.secret, &sec.params, qr_counter(&sec), &name, &title);
 
-    let ret = match goog.qr_code(&secret, &name, &title, width, height, Medium) {
-        Ok(s) => s,
+    let code = match QrCode::with_error_correction_level(url.as_bytes(), EcLevel::M) {
+        Ok(c) => c,
         Err(e) => {
             error!("Error creating qr code: {}", e);
-            return Ok(Response::with((
-                get_json_ct(),
-                status::Ok,
-                object! {
-                    status: false,
-                    message: "Failed to create qr code",
-                }
-                .dump(),
-            )));
+            return Err(ApiError::internal("Failed to create qr code"));
         }
     };
+    let ret = code
+        .render::<svg::Color>()
+        .min_dimensions(width, height)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
 
-    return Ok(Response::with((
-        get_json_ct(),
-        status::Ok,
-        object! {status: true, qr_code: ret}.dump(),
-    )));
+    return Ok(json_response(
+        StatusCode::OK,
+        object! {status: true, qr_code: ret},
+    ));
 }
 
 /// This will create and return a URL string for a rendered qr code.
@@ -376,7 +870,8 @@ fn qr(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Respo
 ///     "api_key": "abc123",
 ///     "ident": "key identifier",
 ///     "name": "name for code, could be company name",
-///     "title": "title for the code"
+///     "title": "title for the code",
+///     "owner": 1  // Optional, admin keys only
 /// }
 /// ```
 ///
@@ -387,30 +882,25 @@ fn qr(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Respo
 ///     "qr_code_url": "http://somewhere.com"
 /// }
 /// ```
-fn qr_url(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<Response> {
-    let goog = GoogleAuthenticator::new();
-    let (_, name, title, secret, width, height) = match get_qr_data(req, conf.clone(), db) {
-        Ok(t) => t,
-        Err(_) => {
-            return Ok(Response::with((
-                get_json_ct(),
-                status::Ok,
-                object! {
-                    status: false,
-                    message: "Failed to create qr url",
-                }
-                .dump(),
-            )));
-        }
-    };
+async fn qr_url(
+    State(app): State<App>,
+    Extension(key): Extension<ApiKey>,
+    Extension(body): Extension<ReqBody>,
+) -> HandlerResult {
+    let (_, name, title, sec, width, height) = get_qr_data(&app, &key, &body).await?;
+    let url = otpauth_url(&sec.secret, &sec.params, qr_counter(&sec), &name, &title);
 
-    let ret = goog.qr_code_url(&secret, &name, &title, width, height, Medium);
+    let ret = format!(
+        "https://chart.googleapis.com/chart?chs={}x{}&chld=M|0&cht=qr&chl={}",
+        width,
+        height,
+        utf8_percent_encode(&url, NON_ALPHANUMERIC)
+    );
 
-    return Ok(Response::with((
-        get_json_ct(),
-        status::Ok,
-        object! {status: true, qr_code_url: ret}.dump(),
-    )));
+    return Ok(json_response(
+        StatusCode::OK,
+        object! {status: true, qr_code_url: ret},
+    ));
 }
 
 /*
@@ -419,96 +909,1017 @@ fn qr_url(req: &mut Request, conf: Arc<Ini>, db: Arc<Mutex<DB>>) -> IronResult<R
 
 /// Simple helper function for getting the secret for a given ident from the
 /// db
-fn get_secret(ident: &str, db: Arc<Mutex<DB>>) -> Result<String, IronError> {
-    // I need a mutable reference to the database for operations
-    let mut mdb = db.lock().unwrap();
+async fn get_secret(ident: &str, owner: Option<i64>, db: &dyn Store) -> Result<Secret, ApiError> {
+    return db
+        .get_secret(owner, ident)
+        .await
+        .map_err(|e| ApiError::from_db("Error getting secret", e));
+}
 
-    let secret = match mdb.get_secret(ident) {
-        Ok((_, sec)) => sec,
-        Err(e) => {
-            error!("Error getting secret: {}", e);
-            return Err(IronError::new(
-                error::HttpError::Method,
-                (status::InternalServerError, "Database error"),
-            ));
+/// Get the owner to scope ident lookups to.  Normal keys only ever see their
+/// own idents, while admin keys see everyone's, optionally narrowed down to
+/// the owner requested.
+fn get_owner(key: &ApiKey, requested: Option<i64>) -> Option<i64> {
+    if key.is_admin() {
+        return requested;
+    }
+
+    return Some(key.id);
+}
+
+/// Get the JSON body for a handler that needs one
+fn require_body(body: &ReqBody) -> Result<&JsonValue, ApiError> {
+    return match &body.0 {
+        Some(b) => Ok(b),
+        None => {
+            error!("Unable to parse request body");
+            Err(ApiError::invalid_body())
         }
     };
+}
+
+/// The response for a request whose handler panicked, so the client still
+/// gets a JSON error rather than a dropped connection
+fn panic_response(err: Box<dyn Any + Send + 'static>) -> Response {
+    let msg = if let Some(s) = err.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = err.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown".to_string()
+    };
+    error!("Request handler panicked: {}", msg);
 
-    return Ok(secret);
+    return ApiError::internal("Internal server error").into_response();
 }
 
-/// Helper function to get all the necessary data for qr code requests
-fn get_qr_data(
-    req: &mut Request,
-    conf: Arc<Ini>,
-    db: Arc<Mutex<DB>>,
-) -> Result<(String, String, String, String, u32, u32), IronError> {
-    let body = match req.get::<Json>() {
-        Ok(Some(b)) => b,
-        _ => {
-            error!("Unable to parse request body");
-            return Err(IronError::new(
-                InvalidReqBody::new("Invalid JSON body"),
-                (status::BadRequest, "Invalid JSON request body"),
-            ));
+/// Build a response with a JSON body
+fn json_response(status: StatusCode, body: JsonValue) -> Response {
+    return (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.dump(),
+    )
+        .into_response();
+}
+
+/// Get one of the `[hotp]` windows, which are checked when the router is
+/// built
+fn get_hotp_setting(conf: &Ini, name: &str) -> u64 {
+    let default = match name {
+        "look_ahead" => 10,
+        _ => 100,
+    };
+
+    return conf.getuint("hotp", name).unwrap().unwrap_or(default);
+}
+
+/// If the ident is locked from verifications, return the response saying
+/// so, where `action` is the response field for the result
+async fn check_locked(
+    app: &App,
+    sec: &Secret,
+    ident: &str,
+    now: i64,
+    action: &str,
+) -> Result<Option<Response>, ApiError> {
+    return match app.db.get_locked_until(sec.id).await {
+        Ok(Some(until)) if until > now => {
+            warn!("Verification attempt for locked ident: {:?}", ident);
+
+            let mut resp = object! {status: true};
+            resp[action] = false.into();
+            resp["reason"] = "locked".into();
+            resp["locked_until"] = until.into();
+            Ok(Some(json_response(StatusCode::OK, resp)))
         }
+        Ok(_) => Ok(None),
+        Err(e) => Err(ApiError::from_db("Error getting the lockout", e)),
+    };
+}
+
+/// Record a failed verification for the ident, locking it if it's had too
+/// many, and return the response for it.  The `action` is the response field
+/// for the result.
+async fn verify_failed(
+    app: &App,
+    sec: &Secret,
+    ident: &str,
+    now: i64,
+    action: &str,
+) -> HandlerResult {
+    let failures = match app.db.record_verify_failure(sec.id).await {
+        Ok(f) => f,
+        Err(e) => return Err(ApiError::from_db("Error recording the failure", e)),
     };
 
+    let mut resp = object! {status: true};
+    resp[action] = false.into();
+    resp["reason"] = "invalid_code".into();
+
+    if let Some(secs) = app.lockout.lock_secs(failures) {
+        let until = now + secs;
+        warn!(
+            "Locking ident {:?} for {}s after {} failures",
+            ident, secs, failures
+        );
+
+        if let Err(e) = app.db.lock_ident(sec.id, until).await {
+            return Err(ApiError::from_db("Error locking the ident", e));
+        }
+
+        resp["locked_until"] = until.into();
+    }
+
+    return Ok(json_response(StatusCode::OK, resp));
+}
+
+/// The counter for the otpauth URL, which only HOTP secrets have
+fn qr_counter(sec: &Secret) -> Option<u64> {
+    return match sec.kind {
+        OtpType::Totp => None,
+        OtpType::Hotp => Some(sec.counter as u64),
+    };
+}
+
+/// Helper function to get all the necessary data for qr code requests
+async fn get_qr_data(
+    app: &App,
+    key: &ApiKey,
+    body: &ReqBody,
+) -> Result<(String, String, String, Secret, u32, u32), ApiError> {
+    let body = require_body(body)?;
+
     let ident = body["ident"].as_str();
     let name = body["name"].as_str();
     let title = body["title"].as_str();
 
-    validate_params(&[ident, name, title])?;
+    validate_params(&[("ident", ident), ("name", name), ("title", title)])?;
 
-    let width = conf.getuint("auth", "default_width").unwrap().unwrap() as u32;
-    let height = conf.getuint("auth", "default_height").unwrap().unwrap() as u32;
+    let width = app
+        .config
+        .getuint("auth", "default_width")
+        .unwrap()
+        .unwrap() as u32;
+    let height = app
+        .config
+        .getuint("auth", "default_height")
+        .unwrap()
+        .unwrap() as u32;
 
-    let secret = match get_secret(ident.unwrap(), db) {
-        Ok(sec) => sec,
-        Err(e) => return Err(e),
-    };
+    let owner = get_owner(key, body["owner"].as_i64());
+
+    let sec = get_secret(ident.unwrap(), owner, app.db.as_ref()).await?;
 
     return Ok((
         ident.unwrap().to_string(),
         name.unwrap().to_string(),
         title.unwrap().to_string(),
-        secret,
+        sec,
         width,
         height,
     ));
 }
 
-fn index_page(_: &mut Request) -> Result<Response, IronError> {
-    let mut resp = Response::new();
-    let page = "<html><head><title>Gauth Server</title></head><body> \
+async fn index_page() -> Html<&'static str> {
+    return Html(
+        "<html><head><title>Gauth Server</title></head><body> \
         <p>You can find the documentation for this at \
         <a href=\"https://github.com/crustymonkey/gauth-server\">https://github.com/crustymonkey/gauth-server</a> \
-        </p></body></html>";
-    resp.set_mut(page.to_string());
-    resp.set_mut(status::Ok);
-    resp.set_mut("text/html".parse::<mime::Mime>().unwrap());
-
-    return Ok(resp);
+        </p></body></html>",
+    );
 }
 
-/// Utility function used to return a JSON content type for responses
-fn get_json_ct() -> mime::Mime {
-    return "application/json".parse::<mime::Mime>().unwrap();
+/// Get the api key from the request headers, either as a bearer token in
+/// `Authorization` or in `X-API-Key`
+fn header_api_key(headers: &HeaderMap) -> Option<String> {
+    let raw = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+    };
+
+    if let Some(auth) = raw("authorization") {
+        if let Some((scheme, token)) = auth.split_once(' ') {
+            if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+                return Some(token.trim().to_string());
+            }
+        }
+    }
+
+    return raw("x-api-key")
+        .filter(|k| !k.is_empty())
+        .map(str::to_string);
 }
 
 /// This is just a convenience function for validating that parameters are
-/// correctly passed in
-fn validate_params<T>(params: &[Option<T>]) -> Result<(), IronError> {
-    for p in params.iter() {
+/// correctly passed in, given their names and values
+fn validate_params<T>(params: &[(&str, Option<T>)]) -> Result<(), ApiError> {
+    for (name, p) in params.iter() {
         // Just go through the params and check for something being none,
         // which will be an error
         if p.is_none() {
-            return Err(IronError::new(
-                InvalidReqBody::new("Request parameters missing"),
-                (status::BadRequest, "Request parameters missing"),
-            ));
+            return Err(ApiError::missing_param(name));
         }
     }
 
     return Ok(());
 }
+
+/// Get an optional parameter, which is `None` if it wasn't passed in but an
+/// error if it was passed with the wrong type
+fn optional_param<'a, T>(
+    body: &'a JsonValue,
+    name: &str,
+    conv: fn(&'a JsonValue) -> Option<T>,
+) -> Result<Option<T>, ApiError> {
+    let val = &body[name];
+    if val.is_null() {
+        return Ok(None);
+    }
+
+    return match conv(val) {
+        Some(v) => Ok(Some(v)),
+        None => Err(ApiError::new(
+            ErrorCode::InvalidParam,
+            format!("Invalid value for the '{}' parameter", name),
+        )),
+    };
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::alib::failstore::{FailStore, Failure};
+    use crate::alib::memstore::MemStore;
+    use crate::alib::store::{ApiKeyStore, SecretStore};
+    use crate::alib::totp::{base32_decode, current_step, hotp, Algorithm, TotpParams};
+    use tower::ServiceExt;
+
+    async fn _test_setup(scopes: &[Scope]) -> (Router, Arc<MemStore>) {
+        return _test_setup_auth(scopes, ClientAuth::ApiKey).await;
+    }
+
+    async fn _test_setup_auth(
+        scopes: &[Scope],
+        client_auth: ClientAuth,
+    ) -> (Router, Arc<MemStore>) {
+        return _test_setup_conf(scopes, &format!("client_auth = {}\n", client_auth)).await;
+    }
+
+    /// The config, with extra settings for the `[auth]` section
+    fn _test_conf(extra: &str) -> Ini {
+        let mut conf = Ini::new();
+        conf.read(format!(
+            "[auth]\n\
+            secret_len = 32\n\
+            default_width = 200\n\
+            default_height = 200\n\
+            verify_window = 1\n\
+            {}",
+            extra
+        ))
+        .unwrap();
+
+        return conf;
+    }
+
+    async fn _test_setup_conf(scopes: &[Scope], extra: &str) -> (Router, Arc<MemStore>) {
+        let store = Arc::new(MemStore::new());
+        store
+            .add_api_key("test.example.com", "abc12345", None, scopes, None)
+            .await
+            .unwrap();
+        let router = get_router_w_routes(_test_conf(extra), store.clone()).unwrap();
+
+        return (router, store);
+    }
+
+    /// Set up with a store that can be made to fail, which already has a
+    /// secret for "test_ident"
+    async fn _test_setup_fail() -> (Router, Arc<FailStore>) {
+        let store = Arc::new(FailStore::new());
+        let id = store
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Admin], None)
+            .await
+            .unwrap();
+        store
+            .create_secret(
+                id,
+                "test_ident",
+                "JBSWY3DPEHPK3PXP",
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        // Every route is hit once per failure mode, more than the default burst
+        let conf = _test_conf("[ratelimit]\nburst = 100\n");
+        let router = get_router_w_routes(conf, store.clone()).unwrap();
+
+        return (router, store);
+    }
+
+    /// Post the JSON body to the router and return the status and raw body
+    async fn _post(router: &Router, path: &str, body: JsonValue) -> (StatusCode, String) {
+        return _post_with(router, path, HeaderMap::new(), None, body).await;
+    }
+
+    /// Post as a client that presented a certificate with the names
+    async fn _post_cert(
+        router: &Router,
+        path: &str,
+        names: &[&str],
+        body: JsonValue,
+    ) -> (StatusCode, String) {
+        return _post_with(router, path, HeaderMap::new(), Some(names), body).await;
+    }
+
+    async fn _post_with(
+        router: &Router,
+        path: &str,
+        headers: HeaderMap,
+        cert: Option<&[&str]>,
+        body: JsonValue,
+    ) -> (StatusCode, String) {
+        let mut req = axum::http::Request::post(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.dump()))
+            .unwrap();
+        req.headers_mut().extend(headers);
+        req.extensions_mut().insert(Peer {
+            addr: Some("127.0.0.1:3000".parse().unwrap()),
+            cert_names: cert.map(|c| c.iter().map(|n| n.to_string()).collect()),
+        });
+
+        let resp = router.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = to_bytes(resp.into_body(), MAX_BODY_SIZE).await.unwrap();
+
+        return (status, String::from_utf8(body.to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_create_and_verify() {
+        let (router, _) = _test_setup(&[Scope::Create, Scope::Verify]).await;
+
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["status"], true);
+        assert_eq!(body["digits"], 6);
+        assert_eq!(body["algorithm"], "SHA1");
+
+        let secret = body["secret"].as_str().unwrap();
+        let code = _code(secret, 30, 6, Algorithm::Sha1);
+        let req = object! {api_key: "abc12345", ident: "test_ident", code: code.clone()};
+
+        let (_, body) = _post(&router, "/verify", req.clone()).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], true);
+
+        // The same code can't be used twice
+        let (_, body) = _post(&router, "/verify", req).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], false);
+        assert_eq!(body["reason"], "replayed");
+
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::CONFLICT);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["status"], false);
+        assert_eq!(body["error"], "duplicate_ident");
+    }
+
+    /// The current code for a secret
+    fn _code(secret: &str, period: u64, digits: u32, algorithm: Algorithm) -> String {
+        let key = base32_decode(secret).unwrap();
+        return hotp(&key, current_step(period), digits, algorithm);
+    }
+
+    #[tokio::test]
+    async fn test_totp_params() {
+        let (router, _) = _test_setup_conf(
+            &[Scope::Create, Scope::Verify, Scope::Qr],
+            "[totp]\nmax_digits = 8\nmax_period = 60\nalgorithms = SHA1, SHA256\n",
+        )
+        .await;
+
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {
+                api_key: "abc12345",
+                ident: "test_ident",
+                digits: 8,
+                period: 60,
+                algorithm: "sha256"
+            },
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["digits"], 8);
+        assert_eq!(body["period"], 60);
+        assert_eq!(body["algorithm"], "SHA256");
+
+        let secret = body["secret"].as_str().unwrap();
+        let code = _code(secret, 60, 8, Algorithm::Sha256);
+        assert_eq!(code.len(), 8);
+        let (_, body) = _post(
+            &router,
+            "/verify",
+            object! {api_key: "abc12345", ident: "test_ident", code: code},
+        )
+        .await;
+        assert_eq!(json::parse(&body).unwrap()["verified"], true);
+
+        let (st, body) = _post(
+            &router,
+            "/qr_url",
+            object! {api_key: "abc12345", ident: "test_ident", name: "Co", title: "t"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        let url = json::parse(&body).unwrap()["qr_code_url"].to_string();
+        assert!(url.contains("algorithm%3DSHA256"));
+        assert!(url.contains("digits%3D8"));
+
+        // Outside of the configured limits
+        let bad = [
+            object! {api_key: "abc12345", ident: "a", digits: 9},
+            object! {api_key: "abc12345", ident: "a", period: 90},
+            object! {api_key: "abc12345", ident: "a", algorithm: "SHA512"},
+            object! {api_key: "abc12345", ident: "a", algorithm: "MD5"},
+            object! {api_key: "abc12345", ident: "a", digits: "eight"},
+            object! {api_key: "abc12345", ident: "a", "type": "motp"},
+        ];
+        for req in bad {
+            let (st, body) = _post(&router, "/create", req.clone()).await;
+            assert_eq!(st, StatusCode::BAD_REQUEST, "{}", req);
+            assert_eq!(json::parse(&body).unwrap()["error"], "invalid_param");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hotp() {
+        let (router, _) = _test_setup_conf(
+            &[Scope::Create, Scope::Verify],
+            "[hotp]\nlook_ahead = 2\nresync_window = 20\n",
+        )
+        .await;
+
+        let (_, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "hotp_ident", "type": "hotp"},
+        )
+        .await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["type"], "hotp");
+        let key = base32_decode(body["secret"].as_str().unwrap()).unwrap();
+        let code = |c: u64| hotp(&key, c, 6, Algorithm::Sha1);
+        let verify = |c: u64| object! {api_key: "abc12345", ident: "hotp_ident", code: code(c)};
+
+        let (_, body) = _post(&router, "/verify", verify(0)).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], true);
+        assert_eq!(body["offset"], 0);
+
+        // Already used, so it's behind the counter now
+        let (_, body) = _post(&router, "/verify", verify(0)).await;
+        assert_eq!(json::parse(&body).unwrap()["reason"], "invalid_code");
+
+        // Within the look ahead of the next counter, 1
+        let (_, body) = _post(&router, "/verify", verify(3)).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], true);
+        assert_eq!(body["offset"], 2);
+
+        // Past the look ahead, so the token needs a resync
+        let (_, body) = _post(&router, "/verify", verify(10)).await;
+        assert_eq!(json::parse(&body).unwrap()["reason"], "invalid_code");
+
+        let (_, body) = _post(
+            &router,
+            "/resync",
+            object! {api_key: "abc12345", ident: "hotp_ident", code: code(10), next_code: code(12)},
+        )
+        .await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["resynced"], false);
+        assert_eq!(body["reason"], "invalid_code");
+
+        let (_, body) = _post(
+            &router,
+            "/resync",
+            object! {api_key: "abc12345", ident: "hotp_ident", code: code(10), next_code: code(11)},
+        )
+        .await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["resynced"], true);
+        assert_eq!(body["counter"], 12);
+
+        let (_, body) = _post(&router, "/verify", verify(12)).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], true);
+        assert_eq!(body["offset"], 0);
+
+        // Only hotp idents have a counter to resync
+        _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "totp_ident"},
+        )
+        .await;
+        let (st, body) = _post(
+            &router,
+            "/resync",
+            object! {api_key: "abc12345", ident: "totp_ident", code: "123456", next_code: "654321"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::BAD_REQUEST);
+        assert_eq!(json::parse(&body).unwrap()["error"], "invalid_param");
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes() {
+        let (router, _) = _test_setup(&[Scope::Create, Scope::Verify]).await;
+        _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+
+        let (st, body) = _post(
+            &router,
+            "/recovery_codes/generate",
+            object! {api_key: "abc12345", ident: "test_ident", count: 3},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        let body = json::parse(&body).unwrap();
+        let codes: Vec<String> = body["codes"].members().map(|c| c.to_string()).collect();
+        assert_eq!(codes.len(), 3);
+
+        // Typed in a bit differently, which is still fine
+        let typed = codes[0].to_lowercase().replace('-', " ");
+        let req = object! {api_key: "abc12345", ident: "test_ident", code: typed};
+        let (_, body) = _post(&router, "/recovery_codes/verify", req.clone()).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], true);
+        assert_eq!(body["remaining"], 2);
+
+        // Each code only works once
+        let (_, body) = _post(&router, "/recovery_codes/verify", req).await;
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["verified"], false);
+        assert_eq!(body["reason"], "invalid_code");
+
+        // A new set replaces the old one
+        let (_, body) = _post(
+            &router,
+            "/recovery_codes/generate",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+        assert_eq!(json::parse(&body).unwrap()["codes"].len(), 10);
+        let (_, body) = _post(
+            &router,
+            "/recovery_codes/verify",
+            object! {api_key: "abc12345", ident: "test_ident", code: codes[1].clone()},
+        )
+        .await;
+        assert_eq!(json::parse(&body).unwrap()["verified"], false);
+
+        let (st, body) = _post(
+            &router,
+            "/recovery_codes/generate",
+            object! {api_key: "abc12345", ident: "missing"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::NOT_FOUND);
+        assert_eq!(json::parse(&body).unwrap()["error"], "unknown_ident");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let (router, _) = _test_setup(&[Scope::Verify]).await;
+
+        let (st, body) = _post(&router, "/verify", object! {api_key: "bogus"}).await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+        assert_eq!(json::parse(&body).unwrap()["error"], "unauthorized");
+
+        let (st, body) = _post(&router, "/verify", object! {api_key: "abc12345"}).await;
+        assert_eq!(st, StatusCode::BAD_REQUEST);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["error"], "missing_param");
+        assert_eq!(body["message"], "Missing the 'ident' parameter");
+
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::FORBIDDEN);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["status"], false);
+        assert_eq!(body["error"], "forbidden");
+
+        // A body that isn't JSON at all
+        let req = axum::http::Request::post("/verify")
+            .header("X-API-Key", "abc12345")
+            .body(Body::from("not json"))
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(resp.into_body(), MAX_BODY_SIZE).await.unwrap();
+        assert_eq!(
+            json::parse(std::str::from_utf8(&body).unwrap()).unwrap()["error"],
+            "invalid_body"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (router, _) =
+            _test_setup_conf(&[Scope::Verify], "[ratelimit]\nrate = 0.01\nburst = 1\n").await;
+
+        let (st, _) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "a"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::FORBIDDEN);
+
+        // Limited before the scope is checked
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "a"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(json::parse(&body).unwrap()["error"], "rate_limited");
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let (router, _) = _test_setup(&[Scope::Create, Scope::Delete]).await;
+        let req = object! {api_key: "abc12345", ident: "test_ident"};
+
+        _post(&router, "/create", req.clone()).await;
+        let (st, _) = _post(&router, "/delete", req.clone()).await;
+        assert_eq!(st, StatusCode::OK);
+
+        let (st, body) = _post(&router, "/delete", req).await;
+        assert_eq!(st, StatusCode::NOT_FOUND);
+        assert_eq!(json::parse(&body).unwrap()["error"], "unknown_ident");
+    }
+
+    #[tokio::test]
+    async fn test_ident_ownership() {
+        let (router, store) = _test_setup(&[Scope::Create, Scope::Verify]).await;
+        store
+            .add_api_key(
+                "other.example.com",
+                "def67890",
+                None,
+                &[Scope::Verify],
+                None,
+            )
+            .await
+            .unwrap();
+
+        _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+
+        let (st, body) = _post(
+            &router,
+            "/verify",
+            object! {api_key: "def67890", ident: "test_ident", code: "123456"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::NOT_FOUND);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["status"], false);
+        assert_eq!(body["error"], "unknown_ident");
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_ident() {
+        let (router, store) = _test_setup(&[Scope::Admin]).await;
+        let other = store
+            .add_api_key(
+                "other.example.com",
+                "def67890",
+                None,
+                &[Scope::Create],
+                None,
+            )
+            .await
+            .unwrap();
+
+        for key in ["abc12345", "def67890"] {
+            let (st, _) = _post(
+                &router,
+                "/create",
+                object! {api_key: key, ident: "test_ident"},
+            )
+            .await;
+            assert_eq!(st, StatusCode::OK);
+        }
+
+        // The admin has to say which owner's ident it means
+        for path in ["/qr_url", "/delete"] {
+            let (st, body) = _post(
+                &router,
+                path,
+                object! {api_key: "abc12345", ident: "test_ident", name: "Co", title: "t"},
+            )
+            .await;
+            assert_eq!(st, StatusCode::BAD_REQUEST, "{}", path);
+            let body = json::parse(&body).unwrap();
+            assert_eq!(body["error"], "invalid_param");
+            assert!(body["message"].as_str().unwrap().contains("'owner'"));
+        }
+
+        let (st, _) = _post(
+            &router,
+            "/delete",
+            object! {api_key: "abc12345", ident: "test_ident", owner: other},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_create_for_owner() {
+        let (router, store) = _test_setup(&[Scope::Admin]).await;
+        let other = store
+            .add_api_key("other.example.com", "def67890", None, &[Scope::Qr], None)
+            .await
+            .unwrap();
+
+        let (st, _) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident", owner: other},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        let (st, _) = _post(
+            &router,
+            "/qr_url",
+            object! {api_key: "def67890", ident: "test_ident", name: "Co", title: "t"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident", owner: other + 100},
+        )
+        .await;
+        assert_eq!(st, StatusCode::BAD_REQUEST);
+        let body = json::parse(&body).unwrap();
+        assert_eq!(body["error"], "invalid_param");
+        assert!(body["message"].as_str().unwrap().contains("'owner'"));
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_auth() {
+        let (router, store) = _test_setup_auth(&[Scope::Create], ClientAuth::Cert).await;
+        let id = store.get_api_key("abc12345").await.unwrap().id;
+        store
+            .set_api_key_cert(id, Some("svc.example.com"))
+            .await
+            .unwrap();
+        let req = object! {ident: "test_ident"};
+
+        // No certificate, and the api key isn't enough
+        let (st, _) = _post(&router, "/create", req.clone()).await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+        let (st, _) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "test_ident"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+
+        let (st, _) = _post_cert(&router, "/create", &["other.example.com"], req.clone()).await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+
+        let (st, body) = _post_cert(
+            &router,
+            "/create",
+            &["other.example.com", "svc.example.com"],
+            req,
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        assert_eq!(json::parse(&body).unwrap()["status"], true);
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_either_and_both() {
+        let (router, store) = _test_setup_auth(&[Scope::Create], ClientAuth::Either).await;
+        let id = store.get_api_key("abc12345").await.unwrap().id;
+        store
+            .set_api_key_cert(id, Some("svc.example.com"))
+            .await
+            .unwrap();
+
+        // Either works on its own
+        let (st, _) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "one"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+        let (st, _) = _post_cert(
+            &router,
+            "/create",
+            &["svc.example.com"],
+            object! {ident: "two"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+
+        let (router, store) = _test_setup_auth(&[Scope::Create], ClientAuth::Both).await;
+        let id = store.get_api_key("abc12345").await.unwrap().id;
+        store
+            .set_api_key_cert(id, Some("svc.example.com"))
+            .await
+            .unwrap();
+        store
+            .add_api_key(
+                "other.example.com",
+                "def67890",
+                None,
+                &[Scope::Create],
+                None,
+            )
+            .await
+            .unwrap();
+        let names = ["svc.example.com"];
+
+        let (st, _) = _post_cert(&router, "/create", &names, object! {ident: "one"}).await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+        let (st, _) = _post_cert(
+            &router,
+            "/create",
+            &names,
+            object! {api_key: "def67890", ident: "one"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+        let (st, _) = _post_cert(
+            &router,
+            "/create",
+            &names,
+            object! {api_key: "abc12345", ident: "one"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_header_api_key() {
+        let (router, _) = _test_setup_conf(&[Scope::Create], "allow_body_api_key = false\n").await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer abc12345".parse().unwrap());
+        let (st, _) = _post_with(&router, "/create", headers, None, object! {ident: "one"}).await;
+        assert_eq!(st, StatusCode::OK);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-API-Key", "abc12345".parse().unwrap());
+        let (st, _) = _post_with(&router, "/create", headers, None, object! {ident: "two"}).await;
+        assert_eq!(st, StatusCode::OK);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Basic abc12345".parse().unwrap());
+        let (st, _) = _post_with(&router, "/create", headers, None, object! {ident: "three"}).await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+
+        // The body isn't allowed
+        let (st, body) = _post(
+            &router,
+            "/create",
+            object! {api_key: "abc12345", ident: "three"},
+        )
+        .await;
+        assert_eq!(st, StatusCode::UNAUTHORIZED);
+        assert!(body.contains("Authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_db_failures() {
+        let (router, store) = _test_setup_fail().await;
+        // The delete is last, so the ident is still there for the others
+        // once the store recovers
+        let reqs = [
+            ("/create", object! {api_key: "abc12345", ident: "new_ident"}),
+            (
+                "/verify",
+                object! {api_key: "abc12345", ident: "test_ident", code: "123456"},
+            ),
+            (
+                "/qr_url",
+                object! {api_key: "abc12345", ident: "test_ident", name: "Co", title: "t"},
+            ),
+            (
+                "/recovery_codes/generate",
+                object! {api_key: "abc12345", ident: "test_ident"},
+            ),
+            (
+                "/delete",
+                object! {api_key: "abc12345", ident: "test_ident"},
+            ),
+        ];
+        let cases = [
+            (
+                Failure::Unavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "db_unavailable",
+            ),
+            (
+                Failure::Error,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+            (
+                Failure::Panic,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+            (
+                Failure::Poison,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+        ];
+
+        for (failure, status, code) in cases {
+            store.fail_with(Some(failure));
+
+            for (path, req) in &reqs {
+                let (st, body) = _post(&router, path, req.clone()).await;
+                assert_eq!(st, status, "{:?} on {}", failure, path);
+                let body = json::parse(&body).unwrap();
+                assert_eq!(body["status"], false);
+                assert_eq!(body["error"], code, "{:?} on {}", failure, path);
+                // The details of the failure are only logged
+                assert!(!body["message"].as_str().unwrap().contains("syntax"));
+            }
+        }
+        assert!(store.is_poisoned());
+
+        // Nothing is left broken, not even by the poisoned lock
+        store.fail_with(None);
+        for (path, req) in &reqs {
+            let (st, _) = _post(&router, path, req.clone()).await;
+            assert_eq!(st, StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_header_parsing() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(header_api_key(&headers), None);
+
+        headers.insert(header::AUTHORIZATION, "bearer  abc123 ".parse().unwrap());
+        assert_eq!(header_api_key(&headers).as_deref(), Some("abc123"));
+
+        // A non-bearer Authorization falls back to X-API-Key
+        headers.insert(header::AUTHORIZATION, "Basic Zm9vOmJhcg==".parse().unwrap());
+        assert_eq!(header_api_key(&headers), None);
+        headers.insert("X-API-Key", "def456".parse().unwrap());
+        assert_eq!(header_api_key(&headers).as_deref(), Some("def456"));
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Context, Result};
+use configparser::ini::Ini;
+use nix::unistd::{Group, User};
+use std::fs;
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+
+/// Where the server listens, from `[main] listen`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    /// A TCP address, like "127.0.0.1:9005"
+    Tcp(String),
+    /// The path to a unix socket
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// Read the listen address from the config.  This can be
+    /// `unix:/path/to.sock`, `tcp:<ip>:<port>` or just `<ip>:<port>`, and
+    /// falls back to `bind_ip` and `port` if it isn't set.
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        let listen = match conf.get("main", "listen").filter(|l| !l.is_empty()) {
+            Some(l) => l,
+            None => {
+                let ip = conf
+                    .get("main", "bind_ip")
+                    .ok_or_else(|| anyhow!("[main] bind_ip or listen is required"))?;
+                let port = conf
+                    .getuint("main", "port")
+                    .map_err(|e| anyhow!(e))?
+                    .ok_or_else(|| anyhow!("[main] port is required"))?;
+                return Ok(Self::Tcp(format!("{}:{}", ip, port)));
+            }
+        };
+
+        if let Some(path) = listen.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(anyhow!("Missing the socket path in: {}", listen));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        return Ok(Self::Tcp(
+            listen.strip_prefix("tcp:").unwrap_or(&listen).to_string(),
+        ));
+    }
+}
+
+/// The permissions for a unix socket, from the `[main]` config section
+#[derive(Debug, Clone, Default)]
+pub struct SocketPerms {
+    /// The file mode, like 0o660
+    pub mode: Option<u32>,
+    /// The user and group names or ids to own the socket
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl SocketPerms {
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        let mode = match conf.get("main", "socket_mode") {
+            Some(m) => Some(
+                u32::from_str_radix(m.trim_start_matches("0o"), 8)
+                    .map_err(|_| anyhow!("Invalid socket_mode, it must be octal: {}", m))?,
+            ),
+            None => None,
+        };
+
+        return Ok(Self {
+            mode,
+            owner: conf.get("main", "socket_owner"),
+            group: conf.get("main", "socket_group"),
+        });
+    }
+
+    /// Apply the permissions to the socket at the path
+    fn apply(&self, path: &Path) -> Result<()> {
+        let uid = match &self.owner {
+            Some(o) => Some(match o.parse() {
+                Ok(id) => id,
+                Err(_) => User::from_name(o)?
+                    .ok_or_else(|| anyhow!("No such user: {}", o))?
+                    .uid
+                    .as_raw(),
+            }),
+            None => None,
+        };
+        let gid = match &self.group {
+            Some(g) => Some(match g.parse() {
+                Ok(id) => id,
+                Err(_) => Group::from_name(g)?
+                    .ok_or_else(|| anyhow!("No such group: {}", g))?
+                    .gid
+                    .as_raw(),
+            }),
+            None => None,
+        };
+
+        if uid.is_some() || gid.is_some() {
+            chown(path, uid, gid).context("Failed to change the socket's owner")?;
+        }
+
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .context("Failed to change the socket's mode")?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// Listen on the unix socket at the path.  A socket left behind by a
+/// previous run is replaced, but any other file at the path is an error.
+pub fn bind_unix(path: &Path, perms: &SocketPerms) -> Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    perms.apply(path)?;
+
+    return Ok(listener);
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    fn _conf(s: &str) -> Ini {
+        let mut conf = Ini::new();
+        conf.read(s.to_string()).unwrap();
+
+        return conf;
+    }
+
+    #[test]
+    fn test_listen() {
+        let l = |s: &str| Listen::from_config(&_conf(s));
+
+        assert_eq!(
+            l("[main]\nbind_ip = 127.0.0.1\nport = 9005\n").unwrap(),
+            Listen::Tcp("127.0.0.1:9005".to_string())
+        );
+        assert_eq!(
+            l("[main]\nlisten = tcp:0.0.0.0:8000\nport = 9005\n").unwrap(),
+            Listen::Tcp("0.0.0.0:8000".to_string())
+        );
+        assert_eq!(
+            l("[main]\nlisten = [::1]:8000\n").unwrap(),
+            Listen::Tcp("[::1]:8000".to_string())
+        );
+        assert_eq!(
+            l("[main]\nlisten = unix:/run/gauth.sock\n").unwrap(),
+            Listen::Unix(PathBuf::from("/run/gauth.sock"))
+        );
+        assert!(l("[main]\nlisten = unix:\n").is_err());
+        assert!(l("[main]\nport = 9005\n").is_err());
+    }
+
+    #[test]
+    fn test_socket_perms() {
+        let p = SocketPerms::from_config(&_conf("[main]\nsocket_mode = 0660\n")).unwrap();
+        assert_eq!(p.mode, Some(0o660));
+        assert!(p.owner.is_none());
+        assert!(SocketPerms::from_config(&_conf("[main]\nsocket_mode = 0999\n")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let path = std::env::temp_dir().join(format!("gauth-test-{}.sock", std::process::id()));
+        let perms = SocketPerms {
+            mode: Some(0o600),
+            ..SocketPerms::default()
+        };
+
+        let listener = bind_unix(&path, &perms).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Binding again replaces the old socket
+        drop(listener);
+        bind_unix(&path, &perms).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // But never a regular file
+        fs::write(&path, "").unwrap();
+        assert!(bind_unix(&path, &perms).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Result};
+use configparser::ini::Ini;
+
+/// The settings for locking an ident after repeated failed verifications,
+/// from the `[lockout]` config section
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// The number of consecutive failures before the ident is locked
+    pub max_failures: i32,
+    /// How long the first lock lasts, in seconds
+    pub base_secs: i64,
+    /// The cap on how long any lock can last, in seconds
+    pub max_secs: i64,
+}
+
+impl LockoutPolicy {
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        let int = |key: &str, default: i64| -> Result<i64> {
+            return Ok(conf
+                .getint("lockout", key)
+                .map_err(|e| anyhow!(e))?
+                .unwrap_or(default));
+        };
+
+        let ret = Self {
+            // Anything out of range for an i32 is rejected as 0 below
+            max_failures: int("max_failures", 5)?.clamp(0, i32::MAX as i64) as i32,
+            base_secs: int("base_secs", 30)?,
+            max_secs: int("max_secs", 3600)?,
+        };
+
+        if ret.max_failures <= 0 {
+            return Err(anyhow!("[lockout] max_failures must be at least 1"));
+        } else if ret.base_secs <= 0 {
+            return Err(anyhow!("[lockout] base_secs must be at least 1"));
+        } else if ret.base_secs > ret.max_secs {
+            return Err(anyhow!("[lockout] base_secs can't be more than max_secs"));
+        }
+
+        return Ok(ret);
+    }
+
+    /// Return how long to lock an ident for after the given number of
+    /// consecutive failures, or `None` if it shouldn't be locked yet.  The
+    /// lock doubles for every failure past the threshold.
+    pub fn lock_secs(&self, failures: i32) -> Option<i64> {
+        if failures < self.max_failures {
+            return None;
+        }
+
+        // Cap the shift so this can't overflow, the max will apply anyway
+        let shift = (failures - self.max_failures).min(32) as u32;
+        let secs = self.base_secs.saturating_mul(1i64 << shift);
+
+        return Some(secs.min(self.max_secs));
+    }
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn _test_policy() -> LockoutPolicy {
+        return LockoutPolicy {
+            max_failures: 3,
+            base_secs: 30,
+            max_secs: 300,
+        };
+    }
+
+    fn _conf(s: &str) -> Ini {
+        let mut conf = Ini::new();
+        conf.read(s.to_string()).unwrap();
+
+        return conf;
+    }
+
+    #[test]
+    fn test_lock_secs() {
+        let p = _test_policy();
+
+        assert_eq!(p.lock_secs(0), None);
+        assert_eq!(p.lock_secs(2), None);
+        assert_eq!(p.lock_secs(3), Some(30));
+        assert_eq!(p.lock_secs(4), Some(60));
+        assert_eq!(p.lock_secs(5), Some(120));
+        assert_eq!(p.lock_secs(6), Some(240));
+        assert_eq!(p.lock_secs(7), Some(300));
+        assert_eq!(p.lock_secs(1000), Some(300));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let p = LockoutPolicy::from_config(&_conf("[lockout]\n")).unwrap();
+        assert_eq!((p.max_failures, p.base_secs, p.max_secs), (5, 30, 3600));
+
+        assert!(LockoutPolicy::from_config(&_conf("[lockout]\nmax_failures = 0\n")).is_err());
+        assert!(LockoutPolicy::from_config(&_conf("[lockout]\nmax_failures = -1\n")).is_err());
+        assert!(LockoutPolicy::from_config(&_conf("[lockout]\nbase_secs = 0\n")).is_err());
+        assert!(
+            LockoutPolicy::from_config(&_conf("[lockout]\nbase_secs = 60\nmax_secs = 30\n"))
+                .is_err()
+        );
+        assert!(LockoutPolicy::from_config(&_conf("[lockout]\nmax_secs = x\n")).is_err());
+    }
+}
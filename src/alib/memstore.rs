@@ -0,0 +1,707 @@
+use super::crypto::{hash_api_key, verify_api_key, HashedApiKey, HashedCode};
+use super::error::recover_lock;
+use super::ratelimit::RateLimit;
+use super::scope::Scope;
+use super::store::{
+    AmbiguousIdent, ApiKey, ApiKeyStore, DuplicateIdent, MigrationStore, RecoveryCode, Secret,
+    SecretStore, UnknownApiKey, UnknownIdent,
+};
+use super::totp::{OtpType, TotpParams};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+struct MemKey {
+    key: ApiKey,
+    hashed: HashedApiKey,
+}
+
+struct MemSecret {
+    owner: Option<i64>,
+    ident: String,
+    secret: String,
+    params: TotpParams,
+    kind: OtpType,
+    counter: i64,
+    last_step: Option<i64>,
+    failed_attempts: i32,
+    locked_until: Option<i64>,
+    /// The recovery codes, and whether each has been used
+    recovery_codes: Vec<(RecoveryCode, bool)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: i64,
+    keys: BTreeMap<i64, MemKey>,
+    secrets: BTreeMap<i64, MemSecret>,
+}
+
+impl Inner {
+    fn next_id(&mut self) -> i64 {
+        self.next_id += 1;
+        return self.next_id;
+    }
+
+    fn secret(&mut self, id: i64) -> Result<&mut MemSecret> {
+        return self
+            .secrets
+            .get_mut(&id)
+            .ok_or_else(|| anyhow!("No secret with id {}", id));
+    }
+
+    /// Find the id of the one secret matching the ident, for the given owner
+    /// or any owner if `None`
+    fn find_secret(&self, owner: Option<i64>, ident: &str) -> Result<i64> {
+        let mut found = self
+            .secrets
+            .iter()
+            .filter(|(_, s)| s.ident == ident && (owner.is_none() || s.owner == owner))
+            .map(|(id, _)| *id);
+
+        return match (found.next(), found.next()) {
+            (Some(id), None) => Ok(id),
+            (None, _) => Err(UnknownIdent.into()),
+            (Some(_), Some(_)) => Err(AmbiguousIdent.into()),
+        };
+    }
+}
+
+/// A store that keeps everything in memory, which is handy for tests and
+/// for embedding the handlers without a database.  Nothing is persisted and
+/// the secrets are held in plaintext, but the api keys are still hashed.
+#[derive(Default)]
+pub struct MemStore {
+    inner: Mutex<Inner>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // Every method looks up what it needs before it changes anything, and
+        // the changes themselves can't panic.  The most a panic can leave
+        // behind is an id skipped by `next_id()`.
+        return recover_lock(self.inner.lock());
+    }
+
+    /// Panic while holding the lock, poisoning it the way a bug in one of
+    /// the store methods would
+    #[cfg(test)]
+    pub fn panic_while_locked(&self) {
+        let _inner = self.lock();
+        panic!("injected store panic while locked");
+    }
+
+    #[cfg(test)]
+    pub fn is_poisoned(&self) -> bool {
+        return self.inner.is_poisoned();
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for MemStore {
+    async fn add_api_key(
+        &self,
+        host: &str,
+        api_key: &str,
+        rate_limit: Option<&RateLimit>,
+        scopes: &[Scope],
+        expires_at: Option<i64>,
+    ) -> Result<i64> {
+        let mut inner = self.lock();
+        let hashed = hash_api_key(api_key);
+        let id = inner.next_id();
+
+        let key = ApiKey {
+            id,
+            host: host.to_string(),
+            key_id: hashed.key_id.clone(),
+            rate_limit: rate_limit.cloned(),
+            scopes: scopes.to_vec(),
+            cert_name: None,
+            created_at: chrono::Utc::now().timestamp(),
+            last_used_at: None,
+            expires_at,
+            revoked: false,
+        };
+        inner.keys.insert(id, MemKey { key, hashed });
+
+        return Ok(id);
+    }
+
+    async fn get_api_key(&self, api_key: &str) -> Result<ApiKey> {
+        let inner = self.lock();
+
+        for k in inner.keys.values() {
+            if verify_api_key(api_key, &k.hashed.salt, &k.hashed.hash) {
+                return Ok(k.key.clone());
+            }
+        }
+
+        return Err(anyhow!("Invalid api key"));
+    }
+
+    async fn get_api_key_by_id(&self, id: i64) -> Result<ApiKey> {
+        return match self.lock().keys.get(&id) {
+            Some(k) => Ok(k.key.clone()),
+            None => Err(UnknownApiKey.into()),
+        };
+    }
+
+    async fn get_api_key_by_cert(&self, names: &[String]) -> Result<ApiKey> {
+        let inner = self.lock();
+
+        for name in names {
+            let found = inner
+                .keys
+                .values()
+                .find(|k| k.key.cert_name.as_ref() == Some(name));
+            if let Some(k) = found {
+                return Ok(k.key.clone());
+            }
+        }
+
+        return Err(anyhow!("No api key for the client certificate"));
+    }
+
+    async fn set_api_key_cert(&self, id: i64, name: Option<&str>) -> Result<bool> {
+        let mut inner = self.lock();
+
+        if let Some(name) = name {
+            let taken = inner
+                .keys
+                .values()
+                .any(|k| k.key.id != id && k.key.cert_name.as_deref() == Some(name));
+            if taken {
+                return Err(anyhow!("Certificate name {} is already in use", name));
+            }
+        }
+
+        return match inner.keys.get_mut(&id) {
+            Some(k) => {
+                k.key.cert_name = name.map(str::to_string);
+                Ok(true)
+            }
+            None => Ok(false),
+        };
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        return Ok(self.lock().keys.values().map(|k| k.key.clone()).collect());
+    }
+
+    async fn set_api_key_expiry(&self, id: i64, expires_at: Option<i64>) -> Result<bool> {
+        return match self.lock().keys.get_mut(&id) {
+            Some(k) => {
+                k.key.expires_at = expires_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        };
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        return match self.lock().keys.get_mut(&id) {
+            Some(k) => {
+                k.key.revoked = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        };
+    }
+
+    async fn rotate_api_key(&self, id: i64, api_key: &str) -> Result<bool> {
+        return match self.lock().keys.get_mut(&id) {
+            Some(k) if !k.key.revoked => {
+                k.hashed = hash_api_key(api_key);
+                k.key.key_id = k.hashed.key_id.clone();
+                Ok(true)
+            }
+            _ => Ok(false),
+        };
+    }
+
+    async fn touch_api_key(&self, id: i64) -> Result<()> {
+        if let Some(k) = self.lock().keys.get_mut(&id) {
+            k.key.last_used_at = Some(chrono::Utc::now().timestamp());
+        }
+
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl SecretStore for MemStore {
+    async fn create_secret(
+        &self,
+        owner: i64,
+        ident: &str,
+        secret: &str,
+        params: &TotpParams,
+        kind: OtpType,
+    ) -> Result<()> {
+        let mut inner = self.lock();
+
+        if !inner.keys.contains_key(&owner) {
+            return Err(anyhow!("No api key with id {}", owner));
+        }
+
+        if inner.find_secret(Some(owner), ident).is_ok() {
+            return Err(DuplicateIdent.into());
+        }
+
+        let id = inner.next_id();
+        inner.secrets.insert(
+            id,
+            MemSecret {
+                owner: Some(owner),
+                ident: ident.to_string(),
+                secret: secret.to_string(),
+                params: *params,
+                kind,
+                counter: 0,
+                last_step: None,
+                failed_attempts: 0,
+                locked_until: None,
+                recovery_codes: vec![],
+            },
+        );
+
+        return Ok(());
+    }
+
+    async fn delete_secret(&self, owner: Option<i64>, ident: &str) -> Result<()> {
+        let mut inner = self.lock();
+        let id = inner.find_secret(owner, ident)?;
+        inner.secrets.remove(&id);
+
+        return Ok(());
+    }
+
+    async fn get_secret(&self, owner: Option<i64>, ident: &str) -> Result<Secret> {
+        let inner = self.lock();
+        let id = inner.find_secret(owner, ident)?;
+        let sec = &inner.secrets[&id];
+
+        return Ok(Secret {
+            id,
+            secret: sec.secret.clone(),
+            params: sec.params,
+            kind: sec.kind,
+            counter: sec.counter,
+        });
+    }
+
+    async fn get_secret_by_id(&self, id: i64) -> Result<(String, String)> {
+        let mut inner = self.lock();
+        let sec = inner.secret(id)?;
+
+        return Ok((sec.ident.clone(), sec.secret.clone()));
+    }
+
+    async fn assign_unowned_secrets(&self, owner: i64) -> Result<u64> {
+        let mut count = 0;
+
+        for sec in self.lock().secrets.values_mut() {
+            if sec.owner.is_none() {
+                sec.owner = Some(owner);
+                count += 1;
+            }
+        }
+
+        return Ok(count);
+    }
+
+    async fn use_step(&self, id: i64, step: i64) -> Result<bool> {
+        let mut inner = self.lock();
+        let sec = inner.secret(id)?;
+
+        if sec.last_step.is_some_and(|last| last >= step) {
+            return Ok(false);
+        }
+        sec.last_step = Some(step);
+
+        return Ok(true);
+    }
+
+    async fn use_counter(&self, id: i64, counter: i64) -> Result<bool> {
+        let mut inner = self.lock();
+        let sec = inner.secret(id)?;
+
+        if sec.counter > counter {
+            return Ok(false);
+        }
+        sec.counter = counter + 1;
+
+        return Ok(true);
+    }
+
+    async fn set_recovery_codes(&self, id: i64, codes: &[HashedCode]) -> Result<()> {
+        let mut inner = self.lock();
+        // Check the secret exists before using up any ids
+        inner.secret(id)?;
+
+        let codes: Vec<(RecoveryCode, bool)> = codes
+            .iter()
+            .map(|c| {
+                let code = RecoveryCode {
+                    id: inner.next_id(),
+                    hashed: c.clone(),
+                };
+                (code, false)
+            })
+            .collect();
+        inner.secret(id)?.recovery_codes = codes;
+
+        return Ok(());
+    }
+
+    async fn get_recovery_codes(&self, id: i64) -> Result<Vec<RecoveryCode>> {
+        return Ok(self
+            .lock()
+            .secret(id)?
+            .recovery_codes
+            .iter()
+            .filter(|(_, used)| !used)
+            .map(|(c, _)| c.clone())
+            .collect());
+    }
+
+    async fn use_recovery_code(&self, code_id: i64) -> Result<bool> {
+        let mut inner = self.lock();
+        let found = inner
+            .secrets
+            .values_mut()
+            .flat_map(|s| s.recovery_codes.iter_mut())
+            .find(|(c, _)| c.id == code_id);
+
+        return match found {
+            Some((_, used)) if !*used => {
+                *used = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        };
+    }
+
+    async fn get_locked_until(&self, id: i64) -> Result<Option<i64>> {
+        return Ok(self.lock().secret(id)?.locked_until);
+    }
+
+    async fn record_verify_failure(&self, id: i64) -> Result<i32> {
+        let mut inner = self.lock();
+        let sec = inner.secret(id)?;
+        sec.failed_attempts += 1;
+
+        return Ok(sec.failed_attempts);
+    }
+
+    async fn lock_ident(&self, id: i64, until: i64) -> Result<()> {
+        self.lock().secret(id)?.locked_until = Some(until);
+
+        return Ok(());
+    }
+
+    async fn reset_verify_failures(&self, id: i64) -> Result<()> {
+        let mut inner = self.lock();
+        let sec = inner.secret(id)?;
+        sec.failed_attempts = 0;
+        sec.locked_until = None;
+
+        return Ok(());
+    }
+
+    async fn count_stale_secrets(&self) -> Result<i64> {
+        return Ok(0);
+    }
+
+    async fn rotate_secrets_batch(&self, _batch_size: i64) -> Result<u64> {
+        return Ok(0);
+    }
+}
+
+/// There is no schema to migrate
+#[async_trait]
+impl MigrationStore for MemStore {}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::alib::crypto::hash_recovery_code;
+    use crate::alib::totp::Algorithm;
+
+    async fn _test_setup() -> (MemStore, i64, i64) {
+        let store = MemStore::new();
+        let owner = store
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+        let other = store
+            .add_api_key(
+                "other.example.com",
+                "def67890",
+                None,
+                &[Scope::Create],
+                None,
+            )
+            .await
+            .unwrap();
+
+        return (store, owner, other);
+    }
+
+    #[tokio::test]
+    async fn test_secrets() {
+        let (store, owner, other) = _test_setup().await;
+        let ident = "test_ident";
+
+        store
+            .create_secret(
+                owner,
+                ident,
+                "abc123",
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        let err = store
+            .create_secret(
+                owner,
+                ident,
+                "abc123",
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<DuplicateIdent>().is_some());
+        assert!(store
+            .create_secret(-1, "bogus", "abc123", &TotpParams::default(), OtpType::Totp)
+            .await
+            .is_err());
+
+        let sec = store.get_secret(Some(owner), ident).await.unwrap();
+        let id = sec.id;
+        assert_eq!(sec.secret, "abc123");
+        assert_eq!(sec.params, TotpParams::default());
+        let err = store.get_secret(Some(other), ident).await.unwrap_err();
+        assert!(err.downcast_ref::<UnknownIdent>().is_some());
+        assert_eq!(store.get_secret(None, ident).await.unwrap().id, id);
+        assert_eq!(store.get_secret_by_id(id).await.unwrap().0, ident);
+
+        // Once another owner has the ident, a lookup for any owner fails
+        store
+            .create_secret(
+                other,
+                ident,
+                "def456",
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        let err = store.get_secret(None, ident).await.unwrap_err();
+        assert!(err.is::<AmbiguousIdent>());
+        let err = store.delete_secret(None, ident).await.unwrap_err();
+        assert!(err.is::<AmbiguousIdent>());
+
+        let err = store
+            .delete_secret(Some(other), "missing")
+            .await
+            .unwrap_err();
+        assert!(err.is::<UnknownIdent>());
+        store.delete_secret(Some(owner), ident).await.unwrap();
+        assert!(store.get_secret(Some(owner), ident).await.is_err());
+        assert_eq!(
+            store.get_secret(None, ident).await.unwrap().secret,
+            "def456"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_totp_params() {
+        let (store, owner, _) = _test_setup().await;
+        let params = TotpParams {
+            digits: 8,
+            period: 60,
+            algorithm: Algorithm::Sha256,
+        };
+
+        store
+            .create_secret(owner, "test_ident", "abc123", &params, OtpType::Totp)
+            .await
+            .unwrap();
+        let sec = store.get_secret(Some(owner), "test_ident").await.unwrap();
+        assert_eq!(sec.params, params);
+    }
+
+    #[tokio::test]
+    async fn test_hotp_counter() {
+        let (store, owner, _) = _test_setup().await;
+        store
+            .create_secret(
+                owner,
+                "hotp_ident",
+                "abc123",
+                &TotpParams::default(),
+                OtpType::Hotp,
+            )
+            .await
+            .unwrap();
+
+        let sec = store.get_secret(Some(owner), "hotp_ident").await.unwrap();
+        assert_eq!(sec.kind, OtpType::Hotp);
+        assert_eq!(sec.counter, 0);
+
+        // Skipping ahead is fine, but never going back
+        assert!(store.use_counter(sec.id, 3).await.unwrap());
+        assert!(!store.use_counter(sec.id, 3).await.unwrap());
+        assert!(!store.use_counter(sec.id, 2).await.unwrap());
+        assert!(store.use_counter(sec.id, 4).await.unwrap());
+        let sec = store.get_secret(Some(owner), "hotp_ident").await.unwrap();
+        assert_eq!(sec.counter, 5);
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes() {
+        let (store, owner, _) = _test_setup().await;
+
+        store
+            .create_secret(
+                owner,
+                "test_ident",
+                "abc123",
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        let id = store
+            .get_secret(Some(owner), "test_ident")
+            .await
+            .unwrap()
+            .id;
+
+        let hashed = [hash_recovery_code("AAAAA"), hash_recovery_code("BBBBB")];
+        store.set_recovery_codes(id, &hashed).await.unwrap();
+        let codes = store.get_recovery_codes(id).await.unwrap();
+        assert_eq!(codes.len(), 2);
+        assert_eq!(codes[0].hashed.hash, hashed[0].hash);
+
+        assert!(store.use_recovery_code(codes[0].id).await.unwrap());
+        assert!(!store.use_recovery_code(codes[0].id).await.unwrap());
+        assert_eq!(store.get_recovery_codes(id).await.unwrap().len(), 1);
+
+        // New codes replace all the old ones
+        store.set_recovery_codes(id, &hashed[..1]).await.unwrap();
+        assert!(!store.use_recovery_code(codes[1].id).await.unwrap());
+        let codes = store.get_recovery_codes(id).await.unwrap();
+        assert_eq!(codes.len(), 1);
+
+        // And they go with the secret
+        store
+            .delete_secret(Some(owner), "test_ident")
+            .await
+            .unwrap();
+        assert!(!store.use_recovery_code(codes[0].id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_steps_and_lockout() {
+        let (store, owner, _) = _test_setup().await;
+        store
+            .create_secret(
+                owner,
+                "test_ident",
+                "abc123",
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        let id = store
+            .get_secret(Some(owner), "test_ident")
+            .await
+            .unwrap()
+            .id;
+
+        assert!(store.use_step(id, 100).await.unwrap());
+        assert!(!store.use_step(id, 100).await.unwrap());
+        assert!(!store.use_step(id, 99).await.unwrap());
+        assert!(store.use_step(id, 101).await.unwrap());
+
+        assert_eq!(store.record_verify_failure(id).await.unwrap(), 1);
+        assert_eq!(store.record_verify_failure(id).await.unwrap(), 2);
+        store.lock_ident(id, 12345).await.unwrap();
+        assert_eq!(store.get_locked_until(id).await.unwrap(), Some(12345));
+        store.reset_verify_failures(id).await.unwrap();
+        assert_eq!(store.get_locked_until(id).await.unwrap(), None);
+        assert_eq!(store.record_verify_failure(id).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let (store, owner, other) = _test_setup().await;
+
+        assert!(store.api_key_exists("abc12345").await);
+        assert!(!store.api_key_exists("abc12346").await);
+        assert_eq!(store.get_api_key("def67890").await.unwrap().id, other);
+        assert_eq!(store.list_api_keys().await.unwrap().len(), 2);
+
+        store.touch_api_key(owner).await.unwrap();
+        assert!(store
+            .get_api_key_by_id(owner)
+            .await
+            .unwrap()
+            .last_used_at
+            .is_some());
+
+        assert!(store.rotate_api_key(owner, "ghi12345").await.unwrap());
+        assert!(!store.api_key_exists("abc12345").await);
+        assert_eq!(
+            store.get_api_key("ghi12345").await.unwrap().key_id,
+            "ghi12345"
+        );
+
+        assert!(store.set_api_key_expiry(owner, Some(100)).await.unwrap());
+        assert!(!store.get_api_key("ghi12345").await.unwrap().is_active(100));
+        assert!(store.set_api_key_expiry(owner, None).await.unwrap());
+        assert!(store.get_api_key("ghi12345").await.unwrap().is_active(100));
+        assert!(!store.set_api_key_expiry(-1, None).await.unwrap());
+
+        assert!(store.revoke_api_key(owner).await.unwrap());
+        assert!(!store.get_api_key("ghi12345").await.unwrap().is_active(0));
+        assert!(!store.rotate_api_key(owner, "jkl12345").await.unwrap());
+        assert!(!store.revoke_api_key(-1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_cert() {
+        let (store, owner, other) = _test_setup().await;
+        let names = vec!["svc.example.com".to_string(), "svc".to_string()];
+
+        assert!(store.get_api_key_by_cert(&names).await.is_err());
+        assert!(store.set_api_key_cert(owner, Some("svc")).await.unwrap());
+        assert_eq!(store.get_api_key_by_cert(&names).await.unwrap().id, owner);
+        assert!(store.set_api_key_cert(other, Some("svc")).await.is_err());
+
+        // The first name that matches wins
+        assert!(store
+            .set_api_key_cert(other, Some("svc.example.com"))
+            .await
+            .unwrap());
+        assert_eq!(store.get_api_key_by_cert(&names).await.unwrap().id, other);
+
+        assert!(store.set_api_key_cert(other, None).await.unwrap());
+        assert_eq!(store.get_api_key_by_cert(&names).await.unwrap().id, owner);
+        assert!(!store.set_api_key_cert(-1, None).await.unwrap());
+    }
+}
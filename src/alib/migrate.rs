@@ -0,0 +1,136 @@
+use super::store::MigrationStore;
+use anyhow::Result;
+
+/// A versioned schema change, embedded in the binary.  Each store has its
+/// own ordered list, and the applied versions are recorded in the
+/// `schema_migrations` table.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// The name used in the migration's filename, like "0001_initial"
+    pub fn full_name(&self) -> String {
+        return format!("{:04}_{}", self.version, self.name);
+    }
+}
+
+/*
+ * The migrations for each store, in order.  Never edit one that has been
+ * released, add a new one instead.
+ */
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../migrations/postgres/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "client_cert",
+        sql: include_str!("../../migrations/postgres/0002_client_cert.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "totp_params",
+        sql: include_str!("../../migrations/postgres/0003_totp_params.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "hotp",
+        sql: include_str!("../../migrations/postgres/0004_hotp.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "recovery_codes",
+        sql: include_str!("../../migrations/postgres/0005_recovery_codes.sql"),
+    },
+];
+
+#[cfg(feature = "sqlite")]
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../migrations/sqlite/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "client_cert",
+        sql: include_str!("../../migrations/sqlite/0002_client_cert.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "totp_params",
+        sql: include_str!("../../migrations/sqlite/0003_totp_params.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "hotp",
+        sql: include_str!("../../migrations/sqlite/0004_hotp.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "recovery_codes",
+        sql: include_str!("../../migrations/sqlite/0005_recovery_codes.sql"),
+    },
+];
+
+/// Return the migrations that haven't been applied to the store yet
+pub async fn pending<S: MigrationStore + Sync + ?Sized>(
+    store: &S,
+) -> Result<Vec<&'static Migration>> {
+    let applied = store.applied_migrations().await?;
+
+    return Ok(store
+        .migrations()
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect());
+}
+
+/// Apply all the pending migrations in order, returning the number applied.
+/// This is safe to run from multiple servers at once, a migration applied by
+/// another one in the meantime is skipped.
+pub async fn run<S: MigrationStore + Sync + ?Sized>(store: &S) -> Result<usize> {
+    let mut count = 0;
+
+    for m in pending(store).await? {
+        info!("Applying migration {}", m.full_name());
+        if store.apply_migration(m).await? {
+            count += 1;
+        }
+    }
+
+    return Ok(count);
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn _check_order(migrations: &[Migration]) {
+        assert_eq!(migrations[0].version, 1);
+        for w in migrations.windows(2) {
+            assert!(w[0].version < w[1].version, "{}", w[1].full_name());
+        }
+    }
+
+    #[test]
+    fn test_order() {
+        _check_order(POSTGRES);
+        #[cfg(feature = "sqlite")]
+        {
+            _check_order(SQLITE);
+            assert_eq!(POSTGRES.len(), SQLITE.len());
+        }
+
+        assert_eq!(POSTGRES[0].full_name(), "0001_initial");
+    }
+}
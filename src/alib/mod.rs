@@ -1,4 +1,20 @@
 pub mod config;
+pub mod crypto;
 pub mod db;
 pub mod error;
+#[cfg(test)]
+pub mod failstore;
 pub mod handler;
+pub mod listen;
+pub mod lockout;
+pub mod memstore;
+pub mod migrate;
+pub mod ratelimit;
+pub mod recovery;
+pub mod scope;
+pub mod server;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod store;
+pub mod tls;
+pub mod totp;
@@ -0,0 +1,213 @@
+use super::error::recover_lock;
+use configparser::ini::Ini;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Once there are this many buckets, idle ones get pruned
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A token bucket rate limit.  Requests are allowed at a sustained `rate`
+/// per second with bursts of up to `burst` requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Get the global default limit from the `[ratelimit]` config section
+    pub fn from_config(conf: &Ini) -> Self {
+        return Self {
+            rate: conf.getfloat("ratelimit", "rate").unwrap().unwrap_or(10.0),
+            burst: conf.getuint("ratelimit", "burst").unwrap().unwrap_or(20) as u32,
+        };
+    }
+}
+
+/// The bucket keeps the limit it was last checked against, so pruning can
+/// tell whether it's full without knowing which key it belongs to
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    /// Add the tokens accrued since the last request, up to the burst size
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.last = now;
+    }
+
+    fn is_full(&self) -> bool {
+        return self.tokens >= self.limit.burst as f64;
+    }
+}
+
+/// A set of token buckets, one per API key (and optionally per client IP)
+pub struct RateLimiter {
+    pub default: RateLimit,
+    pub per_ip: bool,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default: RateLimit, per_ip: bool) -> Self {
+        return Self {
+            default,
+            per_ip,
+            buckets: Mutex::new(HashMap::new()),
+        };
+    }
+
+    pub fn from_config(conf: &Ini) -> Self {
+        let per_ip = conf
+            .getboolcoerce("ratelimit", "per_ip")
+            .unwrap()
+            .unwrap_or(false);
+
+        return Self::new(RateLimit::from_config(conf), per_ip);
+    }
+
+    /// Build the bucket key for an API key id and client IP
+    pub fn bucket_key(&self, key_id: i64, ip: &str) -> String {
+        if self.per_ip {
+            return format!("{}|{}", key_id, ip);
+        }
+
+        return key_id.to_string();
+    }
+
+    /// Take a token from the bucket for the given key, using the supplied
+    /// limit or the default if there isn't one.  If the bucket is empty, this
+    /// returns the number of seconds until a token will be available.
+    pub fn check(&self, key: &str, limit: Option<&RateLimit>) -> Result<(), u64> {
+        return self.check_at(key, limit, Instant::now());
+    }
+
+    fn check_at(&self, key: &str, limit: Option<&RateLimit>, now: Instant) -> Result<(), u64> {
+        let limit = limit.unwrap_or(&self.default);
+        // At worst a panic leaves one bucket with a stale token count
+        let mut buckets = recover_lock(self.buckets.lock());
+
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(key) {
+            self.prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            last: now,
+            limit: limit.clone(),
+        });
+        // The key's limit may have been changed since the last request
+        bucket.limit = limit.clone();
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if limit.rate <= 0.0 {
+            return Err(u64::MAX);
+        }
+
+        return Err(((1.0 - bucket.tokens) / limit.rate).ceil() as u64);
+    }
+
+    /// Drop any buckets that have refilled completely, since a new bucket
+    /// would be in the same state
+    fn prune(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, b| {
+            b.refill(now);
+            return !b.is_full();
+        });
+    }
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+    use std::time::Duration;
+
+    fn _test_limiter() -> RateLimiter {
+        return RateLimiter::new(
+            RateLimit {
+                rate: 1.0,
+                burst: 2,
+            },
+            false,
+        );
+    }
+
+    #[test]
+    fn test_burst_and_refill() {
+        let rl = _test_limiter();
+        let now = Instant::now();
+
+        assert!(rl.check_at("1", None, now).is_ok());
+        assert!(rl.check_at("1", None, now).is_ok());
+        assert_eq!(rl.check_at("1", None, now), Err(1));
+
+        // Other keys have their own bucket
+        assert!(rl.check_at("2", None, now).is_ok());
+
+        let later = now + Duration::from_millis(1500);
+        assert!(rl.check_at("1", None, later).is_ok());
+        assert!(rl.check_at("1", None, later).is_err());
+    }
+
+    #[test]
+    fn test_key_limit() {
+        let rl = _test_limiter();
+        let now = Instant::now();
+        let limit = RateLimit {
+            rate: 0.1,
+            burst: 1,
+        };
+
+        assert!(rl.check_at("1", Some(&limit), now).is_ok());
+        assert_eq!(rl.check_at("1", Some(&limit), now), Err(10));
+    }
+
+    #[test]
+    fn test_prune() {
+        let rl = _test_limiter();
+        let now = Instant::now();
+        let slow = RateLimit {
+            rate: 0.1,
+            burst: 1,
+        };
+        let fast = RateLimit {
+            rate: 10.0,
+            burst: 50,
+        };
+
+        assert!(rl.check_at("slow", Some(&slow), now).is_ok());
+        assert!(rl.check_at("fast", Some(&fast), now).is_ok());
+        assert!(rl.check_at("default", None, now).is_ok());
+
+        // Each bucket refills at its own rate, up to its own burst
+        let later = now + Duration::from_secs(2);
+        rl.prune(&mut rl.buckets.lock().unwrap(), later);
+        let mut keys: Vec<_> = rl.buckets.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["slow"]);
+        assert!(rl.check_at("slow", Some(&slow), later).is_err());
+    }
+
+    #[test]
+    fn test_bucket_key() {
+        let mut rl = _test_limiter();
+        assert_eq!(rl.bucket_key(5, "127.0.0.1"), "5");
+
+        rl.per_ip = true;
+        assert_eq!(rl.bucket_key(5, "127.0.0.1"), "5|127.0.0.1");
+    }
+}
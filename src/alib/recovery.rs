@@ -0,0 +1,85 @@
+use super::totp::gen_secret;
+use configparser::ini::Ini;
+
+/// The number of base32 characters in a recovery code, not counting the
+/// separator, which gives 50 random bits
+const CODE_LEN: usize = 10;
+
+/// The settings for recovery codes, from the `[recovery]` config section
+#[derive(Debug, Clone)]
+pub struct RecoveryPolicy {
+    /// The number of codes generated when the request doesn't ask for a
+    /// number
+    pub count: u64,
+    /// The most codes a request can ask for
+    pub max_count: u64,
+}
+
+impl RecoveryPolicy {
+    pub fn from_config(conf: &Ini) -> Self {
+        return Self {
+            count: conf.getuint("recovery", "count").unwrap().unwrap_or(10),
+            max_count: conf.getuint("recovery", "max_count").unwrap().unwrap_or(20),
+        };
+    }
+
+    /// Get the number of codes to generate, from the request if it was
+    /// passed in or the config default otherwise, capped at the maximum.
+    /// There is always at least one.
+    pub fn count(&self, requested: Option<u64>) -> u64 {
+        return requested
+            .unwrap_or(self.count)
+            .clamp(1, self.max_count.max(1));
+    }
+}
+
+/// Generate a new recovery code, split in two like "ABCDE-FGHIJ" so it's
+/// easier to copy down
+pub fn gen_code() -> String {
+    let code = gen_secret(CODE_LEN);
+
+    return format!("{}-{}", &code[..CODE_LEN / 2], &code[CODE_LEN / 2..]);
+}
+
+/// Normalize a recovery code as it was typed in, ignoring case, whitespace
+/// and separators.  Codes are always hashed and checked in this form.
+pub fn normalize(code: &str) -> String {
+    return code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        let p = RecoveryPolicy {
+            count: 10,
+            max_count: 20,
+        };
+
+        assert_eq!(p.count(None), 10);
+        assert_eq!(p.count(Some(5)), 5);
+        assert_eq!(p.count(Some(50)), 20);
+        assert_eq!(p.count(Some(0)), 1);
+    }
+
+    #[test]
+    fn test_codes() {
+        let code = gen_code();
+        assert_eq!(code.len(), CODE_LEN + 1);
+        assert_eq!(&code[5..6], "-");
+        assert_ne!(gen_code(), code);
+
+        assert_eq!(normalize(&code).len(), CODE_LEN);
+        assert_eq!(normalize(" abcde-fghij\n"), "ABCDEFGHIJ");
+        assert_eq!(normalize("ABCDE FGHIJ"), normalize("abcde-fghij"));
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// The permissions an API key can have.  Each authenticated route requires
+/// one of these, and `Admin` implies all of them, along with being able to
+/// see the idents of every other key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Create,
+    Verify,
+    Qr,
+    Delete,
+    Admin,
+}
+
+/// The scopes a new key gets if none are specified
+pub const DEFAULT_SCOPES: &str = "create,verify,qr,delete";
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            Scope::Create => "create",
+            Scope::Verify => "verify",
+            Scope::Qr => "qr",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        };
+    }
+
+    /// Parse a comma separated list of scopes, like "create,qr"
+    pub fn parse_list(s: &str) -> Result<Vec<Scope>> {
+        let mut ret = vec![];
+
+        for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let scope = part.parse::<Scope>()?;
+            if !ret.contains(&scope) {
+                ret.push(scope);
+            }
+        }
+
+        return Ok(ret);
+    }
+
+    /// Format a list of scopes as a comma separated string for storage
+    pub fn join(scopes: &[Scope]) -> String {
+        return scopes
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<&str>>()
+            .join(",");
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        return match s.to_lowercase().as_str() {
+            "create" => Ok(Scope::Create),
+            "verify" => Ok(Scope::Verify),
+            "qr" => Ok(Scope::Qr),
+            "delete" => Ok(Scope::Delete),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(anyhow!("Invalid scope: {}", s)),
+        };
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", self.as_str());
+    }
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() {
+        let scopes = Scope::parse_list("create, qr,,QR").unwrap();
+        assert_eq!(scopes, vec![Scope::Create, Scope::Qr]);
+        assert_eq!(Scope::join(&scopes), "create,qr");
+
+        assert!(Scope::parse_list("create,bogus").is_err());
+        assert!(Scope::parse_list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let scopes = Scope::parse_list(DEFAULT_SCOPES).unwrap();
+        assert_eq!(scopes.len(), 4);
+        assert!(!scopes.contains(&Scope::Admin));
+    }
+}
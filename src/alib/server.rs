@@ -0,0 +1,168 @@
+use super::listen::{bind_unix, Listen, SocketPerms};
+use super::tls::TlsServer;
+use anyhow::{Context, Result};
+use axum::{extract::Request, Router};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::{rt::TokioIo, server::graceful::GracefulShutdown, server::graceful::Watcher};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tower::Service;
+
+/// How long to wait for the requests in progress to finish at shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client on the other end of a connection.  This is added to every
+/// request on the connection as an extension.
+#[derive(Debug, Clone, Default)]
+pub struct Peer {
+    /// The client's address, which unix socket clients don't have
+    pub addr: Option<SocketAddr>,
+    /// The names from the client's TLS certificate, if it presented one
+    pub cert_names: Option<Vec<String>>,
+}
+
+/// The socket the server accepts connections on
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub async fn bind(listen: &Listen, perms: &SocketPerms) -> Result<Self> {
+        return match listen {
+            Listen::Tcp(addr) => {
+                Ok(Self::Tcp(TcpListener::bind(addr).await.with_context(
+                    || format!("Failed to listen on {}", addr),
+                )?))
+            }
+            Listen::Unix(path) => Ok(Self::Unix(bind_unix(path, perms)?)),
+        };
+    }
+
+    async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        return match self {
+            Self::Tcp(l) => {
+                let (sock, addr) = l.accept().await?;
+                Ok((Stream::Tcp(sock), Some(addr)))
+            }
+            Self::Unix(l) => {
+                let (sock, _) = l.accept().await?;
+                Ok((Stream::Unix(sock), None))
+            }
+        };
+    }
+}
+
+/// Serve the routes on the listener, over TLS if `tls` is set, until
+/// `shutdown` completes.  Then stop accepting connections and give the
+/// requests in progress a chance to finish.
+pub async fn serve(
+    listener: Listener,
+    tls: Option<TlsServer>,
+    routes: Router,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (sock, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(s) => s,
+                Err(e) => {
+                    // Most likely out of file descriptors, so back off
+                    error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let watcher = graceful.watcher();
+        let routes = routes.clone();
+        let tls = tls.clone();
+
+        tokio::spawn(async move {
+            match (sock, tls) {
+                (Stream::Tcp(sock), Some(tls)) => match tls.accept(sock).await {
+                    Ok((stream, cert_names)) => {
+                        if let Some(names) = &cert_names {
+                            debug!("Client certificate from {:?} for {:?}", addr, names);
+                        }
+                        serve_conn(stream, Peer { addr, cert_names }, routes, watcher).await;
+                    }
+                    Err(e) => debug!("TLS handshake with {:?} failed: {:#}", addr, e),
+                },
+                (Stream::Tcp(sock), None) => {
+                    serve_conn(
+                        sock,
+                        Peer {
+                            addr,
+                            cert_names: None,
+                        },
+                        routes,
+                        watcher,
+                    )
+                    .await;
+                }
+                (Stream::Unix(sock), _) => {
+                    serve_conn(sock, Peer::default(), routes, watcher).await;
+                }
+            }
+        });
+    }
+
+    // Stop accepting new connections while the old ones finish
+    drop(listener);
+    info!("Waiting for {} connection(s) to finish", graceful.count());
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("Gave up waiting for the connections to finish");
+    }
+
+    return Ok(());
+}
+
+/// Serve the HTTP requests on a connection until the client closes it or
+/// the server shuts down
+async fn serve_conn<I>(io: I, peer: Peer, routes: Router, watcher: Watcher)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let addr = peer.addr;
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(peer.clone());
+        routes.clone().call(req)
+    });
+
+    let conn = http1::Builder::new().serve_connection(TokioIo::new(io), service);
+    if let Err(e) = watcher.watch(conn).await {
+        debug!("Error serving the connection from {:?}: {}", addr, e);
+    }
+}
+
+/// Wait for a SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate()).expect("Failed to set up the SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = term.recv() => (),
+    }
+
+    info!("Shutting down");
+}
@@ -0,0 +1,1115 @@
+use super::crypto::{api_key_id, hash_api_key, verify_api_key, Cipher, HashedCode, Sealed};
+use super::error::recover_lock;
+use super::migrate::{self, Migration};
+use super::ratelimit::RateLimit;
+use super::scope::Scope;
+use super::store::{
+    AmbiguousIdent, ApiKey, ApiKeyStore, DuplicateIdent, MigrationStore, RecoveryCode, Secret,
+    SecretStore, UnknownApiKey, UnknownIdent,
+};
+use super::totp::{OtpType, TotpParams};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rusqlite::{ffi, params, Connection, ErrorCode, OptionalExtension, Row, TransactionBehavior};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MIGRATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        applied_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+    )";
+
+/// How often, in seconds, to update the last used time for an API key
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// The SQLite implementation of the stores, for deployments too small to
+/// justify running Postgres.  The database file is created if it doesn't
+/// exist, but the tables are created by the migrations.
+///
+/// rusqlite only has a blocking api, so every query runs on tokio's blocking
+/// thread pool with the one shared connection.
+pub struct SqliteDB {
+    conn: Arc<Mutex<Connection>>,
+    cipher: Cipher,
+}
+
+impl SqliteDB {
+    pub fn open(path: &str, cipher: Cipher) -> Result<Self> {
+        let conn = Connection::open(path)?;
+
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+
+        return Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            cipher,
+        });
+    }
+
+    /// Run `f` with the connection on the blocking thread pool.  Anything it
+    /// needs from the caller has to be moved in.
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection, &Cipher) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let cipher = self.cipher.clone();
+
+        // A `Transaction` rolls back when it's dropped as a panic in `f`
+        // unwinds, and outside of one each statement commits on its own
+        return tokio::task::spawn_blocking(move || {
+            let mut conn = recover_lock(conn.lock());
+            return f(&mut conn, &cipher);
+        })
+        .await?;
+    }
+}
+
+/// Decrypt a token read from the db
+fn unseal(cipher: &Cipher, ident: &str, row: &Row) -> Result<String> {
+    let token: String = row.get("token")?;
+    let dek = match row.get::<_, Option<String>>("dek")? {
+        Some(d) => d,
+        None => {
+            warn!("Secret for {} is stored in plaintext", ident);
+            return Ok(token);
+        }
+    };
+
+    return cipher.open(
+        row.get("owner_id")?,
+        ident,
+        &Sealed {
+            dek,
+            token,
+            key_version: row.get("key_version")?,
+        },
+    );
+}
+
+#[async_trait]
+impl ApiKeyStore for SqliteDB {
+    async fn add_api_key(
+        &self,
+        host: &str,
+        api_key: &str,
+        rate_limit: Option<&RateLimit>,
+        scopes: &[Scope],
+        expires_at: Option<i64>,
+    ) -> Result<i64> {
+        let q = "INSERT INTO loc_auth \
+            (host, key_id, key_salt, key_hash, rate_limit, rate_burst, scopes, \
+            created_at, expires_at) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+        let now = chrono::Utc::now().timestamp();
+        let hashed = hash_api_key(api_key);
+        let rate = rate_limit.map(|l| l.rate);
+        let burst = rate_limit.map(|l| l.burst);
+        let host = host.to_string();
+        let scopes = Scope::join(scopes);
+
+        return self
+            .run(move |conn, _| {
+                conn.execute(
+                    q,
+                    params![
+                        host,
+                        hashed.key_id,
+                        hashed.salt,
+                        hashed.hash,
+                        rate,
+                        burst,
+                        scopes,
+                        now,
+                        expires_at,
+                    ],
+                )?;
+
+                Ok(conn.last_insert_rowid())
+            })
+            .await;
+    }
+
+    async fn get_api_key(&self, api_key: &str) -> Result<ApiKey> {
+        let q = "SELECT * FROM loc_auth WHERE key_id = ?1";
+        let api_key = api_key.to_string();
+
+        return self
+            .run(move |conn, _| {
+                let mut stmt = conn.prepare(q)?;
+                let mut rows = stmt.query([api_key_id(&api_key)])?;
+
+                while let Some(row) = rows.next()? {
+                    let salt: String = row.get("key_salt")?;
+                    let hash: String = row.get("key_hash")?;
+
+                    if verify_api_key(&api_key, &salt, &hash) {
+                        return api_key_from_row(row);
+                    }
+                }
+
+                Err(anyhow!("Invalid api key"))
+            })
+            .await;
+    }
+
+    async fn get_api_key_by_id(&self, id: i64) -> Result<ApiKey> {
+        let q = "SELECT * FROM loc_auth WHERE id = ?1 AND key_hash IS NOT NULL";
+
+        return self
+            .run(move |conn, _| {
+                let mut stmt = conn.prepare(q)?;
+                let mut rows = stmt.query([id])?;
+
+                let ret = match rows.next()? {
+                    Some(row) => api_key_from_row(row),
+                    None => Err(UnknownApiKey.into()),
+                };
+                ret
+            })
+            .await;
+    }
+
+    async fn get_api_key_by_cert(&self, names: &[String]) -> Result<ApiKey> {
+        let q = "SELECT * FROM loc_auth WHERE cert_name = ?1 AND key_hash IS NOT NULL";
+        let names = names.to_vec();
+
+        return self
+            .run(move |conn, _| {
+                let mut stmt = conn.prepare(q)?;
+
+                for name in &names {
+                    if let Some(row) = stmt.query([name])?.next()? {
+                        return api_key_from_row(row);
+                    }
+                }
+
+                Err(anyhow!("No api key for the client certificate"))
+            })
+            .await;
+    }
+
+    async fn set_api_key_cert(&self, id: i64, name: Option<&str>) -> Result<bool> {
+        let q = "UPDATE loc_auth SET cert_name = ?1 WHERE id = ?2";
+        let name = name.map(str::to_string);
+
+        return self
+            .run(move |conn, _| Ok(conn.execute(q, params![name, id])? > 0))
+            .await;
+    }
+
+    async fn set_api_key_expiry(&self, id: i64, expires_at: Option<i64>) -> Result<bool> {
+        let q = "UPDATE loc_auth SET expires_at = ?1 WHERE id = ?2";
+
+        return self
+            .run(move |conn, _| Ok(conn.execute(q, params![expires_at, id])? > 0))
+            .await;
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let q = "SELECT * FROM loc_auth WHERE key_hash IS NOT NULL ORDER BY id";
+
+        return self
+            .run(move |conn, _| {
+                let mut stmt = conn.prepare(q)?;
+                let mut rows = stmt.query([])?;
+
+                let mut ret = vec![];
+                while let Some(row) = rows.next()? {
+                    ret.push(api_key_from_row(row)?);
+                }
+
+                Ok(ret)
+            })
+            .await;
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        let q = "UPDATE loc_auth SET revoked = TRUE WHERE id = ?1";
+
+        return self
+            .run(move |conn, _| Ok(conn.execute(q, [id])? > 0))
+            .await;
+    }
+
+    async fn rotate_api_key(&self, id: i64, api_key: &str) -> Result<bool> {
+        let q = "UPDATE loc_auth SET key_id = ?1, key_salt = ?2, key_hash = ?3 \
+            WHERE id = ?4 AND NOT revoked";
+        let hashed = hash_api_key(api_key);
+
+        return self
+            .run(move |conn, _| {
+                let count =
+                    conn.execute(q, params![hashed.key_id, hashed.salt, hashed.hash, id])?;
+
+                Ok(count > 0)
+            })
+            .await;
+    }
+
+    /// Record that an api key was just used.  This only writes to the db if
+    /// the last update was a while ago so that busy keys don't cause a write
+    /// on every request.
+    async fn touch_api_key(&self, id: i64) -> Result<()> {
+        let q = "UPDATE loc_auth SET last_used_at = ?2 \
+            WHERE id = ?1 AND (last_used_at IS NULL OR last_used_at < ?3)";
+        let now = chrono::Utc::now().timestamp();
+
+        return self
+            .run(move |conn, _| {
+                conn.execute(q, params![id, now, now - LAST_USED_RESOLUTION])?;
+
+                Ok(())
+            })
+            .await;
+    }
+}
+
+#[async_trait]
+impl SecretStore for SqliteDB {
+    async fn create_secret(
+        &self,
+        owner: i64,
+        ident: &str,
+        secret: &str,
+        params: &TotpParams,
+        kind: OtpType,
+    ) -> Result<()> {
+        let q = "INSERT INTO secrets (owner_id, ident, token, dek, key_version, \
+            digits, period, algorithm, type) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+        let sealed = self.cipher.seal(Some(owner), ident, secret)?;
+        let ident = ident.to_string();
+        let (algorithm, kind) = (params.algorithm.to_string(), kind.to_string());
+        let (digits, period) = (params.digits, params.period as i64);
+
+        return self
+            .run(move |conn, _| {
+                let res = conn.execute(
+                    q,
+                    params![
+                        owner,
+                        ident,
+                        sealed.token,
+                        sealed.dek,
+                        sealed.key_version,
+                        digits,
+                        period,
+                        algorithm,
+                        kind,
+                    ],
+                );
+
+                match res {
+                    Ok(_) => Ok(()),
+                    Err(e) if is_unique_violation(&e) => Err(DuplicateIdent.into()),
+                    Err(e) => Err(e.into()),
+                }
+            })
+            .await;
+    }
+
+    async fn delete_secret(&self, owner: Option<i64>, ident: &str) -> Result<()> {
+        let q = "SELECT id FROM secrets WHERE ident = ?1 AND (?2 IS NULL OR owner_id = ?2)";
+        let ident = ident.to_string();
+
+        return self
+            .run(move |conn, _| {
+                let tx = conn.transaction()?;
+
+                // Unlike Postgres, a subquery returning multiple rows isn't an
+                // error in SQLite, so make sure this can't delete the idents of
+                // multiple owners at once
+                let ids = tx
+                    .prepare(q)?
+                    .query_map(params![ident, owner], |r| r.get::<_, i64>(0))?
+                    .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+                if ids.len() > 1 {
+                    return Err(AmbiguousIdent.into());
+                }
+
+                let id = match ids.first() {
+                    Some(id) => id,
+                    None => return Err(UnknownIdent.into()),
+                };
+                tx.execute("DELETE FROM secrets WHERE id = ?1", [id])?;
+
+                tx.commit()?;
+
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn get_secret(&self, owner: Option<i64>, ident: &str) -> Result<Secret> {
+        let q = "SELECT id, owner_id, token, dek, key_version, digits, period, \
+            algorithm, type, counter \
+            FROM secrets WHERE ident = ?1 AND (?2 IS NULL OR owner_id = ?2)";
+        let ident = ident.to_string();
+
+        return self
+            .run(move |conn, cipher| {
+                let mut stmt = conn.prepare(q)?;
+                let mut rows = stmt.query(params![ident, owner])?;
+
+                let row = match rows.next()? {
+                    Some(r) => r,
+                    None => return Err(UnknownIdent.into()),
+                };
+                let sec = Secret {
+                    id: row.get("id")?,
+                    secret: unseal(cipher, &ident, row)?,
+                    params: TotpParams {
+                        digits: row.get("digits")?,
+                        period: row.get::<_, i64>("period")? as u64,
+                        algorithm: row.get::<_, String>("algorithm")?.parse()?,
+                    },
+                    kind: row.get::<_, String>("type")?.parse()?,
+                    counter: row.get("counter")?,
+                };
+
+                if rows.next()?.is_some() {
+                    return Err(AmbiguousIdent.into());
+                }
+
+                Ok(sec)
+            })
+            .await;
+    }
+
+    async fn get_secret_by_id(&self, id: i64) -> Result<(String, String)> {
+        let q = "SELECT ident, owner_id, token, dek, key_version FROM secrets WHERE id = ?1";
+
+        return self
+            .run(move |conn, cipher| {
+                let mut stmt = conn.prepare(q)?;
+                let mut rows = stmt.query([id])?;
+
+                let row = match rows.next()? {
+                    Some(r) => r,
+                    None => return Err(anyhow!("No secret with id {}", id)),
+                };
+                let ident: String = row.get("ident")?;
+                let token = unseal(cipher, &ident, row)?;
+
+                Ok((ident, token))
+            })
+            .await;
+    }
+
+    async fn assign_unowned_secrets(&self, owner: i64) -> Result<u64> {
+        // Plaintext rows just need the owner, they're sealed for it later
+        let plain = "UPDATE secrets SET owner_id = ?1 WHERE owner_id IS NULL AND dek IS NULL";
+        let q = "SELECT id, ident, owner_id, token, dek, key_version FROM secrets \
+            WHERE owner_id IS NULL";
+        let upd = "UPDATE secrets SET owner_id = ?1, token = ?2, dek = ?3, key_version = ?4 \
+            WHERE id = ?5";
+
+        return self
+            .run(move |conn, cipher| {
+                let tx = conn.transaction()?;
+                let mut count = tx.execute(plain, [owner])? as u64;
+
+                // The owner is bound to sealed tokens, so they have to be
+                // sealed again
+                {
+                    let mut stmt = tx.prepare(q)?;
+                    let mut rows = stmt.query([])?;
+
+                    while let Some(row) = rows.next()? {
+                        let id: i64 = row.get("id")?;
+                        let ident: String = row.get("ident")?;
+                        let token = unseal(cipher, &ident, row)?;
+                        let sealed = cipher.seal(Some(owner), &ident, &token)?;
+
+                        count += tx.execute(
+                            upd,
+                            params![owner, sealed.token, sealed.dek, sealed.key_version, id],
+                        )? as u64;
+                    }
+                }
+
+                tx.commit()?;
+
+                Ok(count)
+            })
+            .await;
+    }
+
+    async fn use_step(&self, id: i64, step: i64) -> Result<bool> {
+        let q = "UPDATE secrets SET last_step = ?2 \
+            WHERE id = ?1 AND (last_step IS NULL OR last_step < ?2)";
+
+        return self
+            .run(move |conn, _| Ok(conn.execute(q, [id, step])? > 0))
+            .await;
+    }
+
+    async fn use_counter(&self, id: i64, counter: i64) -> Result<bool> {
+        let q = "UPDATE secrets SET counter = ?2 + 1 WHERE id = ?1 AND counter <= ?2";
+
+        return self
+            .run(move |conn, _| Ok(conn.execute(q, [id, counter])? > 0))
+            .await;
+    }
+
+    async fn set_recovery_codes(&self, id: i64, codes: &[HashedCode]) -> Result<()> {
+        let del = "DELETE FROM recovery_codes WHERE secret_id = ?1";
+        let ins = "INSERT INTO recovery_codes (secret_id, code_salt, code_hash) \
+            VALUES (?1, ?2, ?3)";
+        let codes = codes.to_vec();
+
+        return self
+            .run(move |conn, _| {
+                let tx = conn.transaction()?;
+
+                tx.execute(del, [id])?;
+                for code in codes {
+                    tx.execute(ins, params![id, code.salt, code.hash])?;
+                }
+
+                tx.commit()?;
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn get_recovery_codes(&self, id: i64) -> Result<Vec<RecoveryCode>> {
+        let q = "SELECT id, code_salt, code_hash FROM recovery_codes \
+            WHERE secret_id = ?1 AND used_at IS NULL ORDER BY id";
+
+        return self
+            .run(move |conn, _| {
+                let mut stmt = conn.prepare(q)?;
+                let codes = stmt
+                    .query_map([id], |r| {
+                        Ok(RecoveryCode {
+                            id: r.get("id")?,
+                            hashed: HashedCode {
+                                salt: r.get("code_salt")?,
+                                hash: r.get("code_hash")?,
+                            },
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                Ok(codes)
+            })
+            .await;
+    }
+
+    async fn use_recovery_code(&self, code_id: i64) -> Result<bool> {
+        let q = "UPDATE recovery_codes SET used_at = CAST(strftime('%s', 'now') AS INTEGER) \
+            WHERE id = ?1 AND used_at IS NULL";
+
+        return self
+            .run(move |conn, _| Ok(conn.execute(q, [code_id])? > 0))
+            .await;
+    }
+
+    async fn get_locked_until(&self, id: i64) -> Result<Option<i64>> {
+        let q = "SELECT locked_until FROM secrets WHERE id = ?1";
+
+        return self
+            .run(move |conn, _| {
+                let until = conn
+                    .query_row(q, [id], |r| r.get::<_, Option<i64>>(0))
+                    .optional()?;
+
+                until.ok_or_else(|| anyhow!("No secret with id {}", id))
+            })
+            .await;
+    }
+
+    async fn record_verify_failure(&self, id: i64) -> Result<i32> {
+        let q = "UPDATE secrets SET failed_attempts = failed_attempts + 1 \
+            WHERE id = ?1 RETURNING failed_attempts";
+
+        return self
+            .run(move |conn, _| Ok(conn.query_row(q, [id], |r| r.get(0))?))
+            .await;
+    }
+
+    async fn lock_ident(&self, id: i64, until: i64) -> Result<()> {
+        let q = "UPDATE secrets SET locked_until = ?2 WHERE id = ?1";
+
+        return self
+            .run(move |conn, _| {
+                conn.execute(q, [id, until])?;
+
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn reset_verify_failures(&self, id: i64) -> Result<()> {
+        let q = "UPDATE secrets SET failed_attempts = 0, locked_until = NULL \
+            WHERE id = ?1";
+
+        return self
+            .run(move |conn, _| {
+                conn.execute(q, [id])?;
+
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn count_stale_secrets(&self) -> Result<i64> {
+        let q = "SELECT COUNT(*) FROM secrets \
+            WHERE dek IS NOT NULL AND key_version <> ?1";
+
+        return self
+            .run(
+                move |conn, cipher| Ok(conn.query_row(q, [cipher.active_version()], |r| r.get(0))?),
+            )
+            .await;
+    }
+
+    /// SQLite locks the whole database for writes, so each batch blocks the
+    /// server briefly.  Keep the batches small on a busy server.
+    async fn rotate_secrets_batch(&self, batch_size: i64) -> Result<u64> {
+        let q = "SELECT id, ident, owner_id, token, dek, key_version FROM secrets \
+            WHERE dek IS NOT NULL AND key_version <> ?1 \
+            ORDER BY id LIMIT ?2";
+        let upd = "UPDATE secrets SET dek = ?1, key_version = ?2 WHERE id = ?3";
+
+        return self
+            .run(move |conn, cipher| {
+                let active = cipher.active_version();
+                let tx = conn.transaction()?;
+                let mut count = 0;
+
+                {
+                    let mut stmt = tx.prepare(q)?;
+                    let mut rows = stmt.query(params![active, batch_size])?;
+
+                    while let Some(row) = rows.next()? {
+                        let id: i64 = row.get("id")?;
+                        let ident: String = row.get("ident")?;
+                        let sealed = Sealed {
+                            dek: row.get("dek")?,
+                            token: row.get("token")?,
+                            key_version: row.get("key_version")?,
+                        };
+                        let sealed = cipher.rewrap(row.get("owner_id")?, &ident, &sealed)?;
+
+                        count +=
+                            tx.execute(upd, params![sealed.dek, sealed.key_version, id])? as u64;
+                    }
+                }
+
+                tx.commit()?;
+
+                Ok(count)
+            })
+            .await;
+    }
+}
+
+/// Build an `ApiKey` from a full `loc_auth` row
+fn api_key_from_row(row: &Row) -> Result<ApiKey> {
+    let rate: Option<f64> = row.get("rate_limit")?;
+    let burst: Option<u32> = row.get("rate_burst")?;
+    let scopes: String = row.get("scopes")?;
+
+    return Ok(ApiKey {
+        id: row.get("id")?,
+        host: row.get("host")?,
+        key_id: row.get("key_id")?,
+        // If a burst isn't set, allow at least a second's worth
+        rate_limit: rate.map(|r| RateLimit {
+            rate: r,
+            burst: burst.unwrap_or(r.ceil().max(1.0) as u32),
+        }),
+        scopes: Scope::parse_list(&scopes)?,
+        cert_name: row.get("cert_name")?,
+        created_at: row.get("created_at")?,
+        last_used_at: row.get("last_used_at")?,
+        expires_at: row.get("expires_at")?,
+        revoked: row.get("revoked")?,
+    });
+}
+
+fn is_unique_violation(e: &rusqlite::Error) -> bool {
+    return match e {
+        rusqlite::Error::SqliteFailure(f, _) => {
+            f.code == ErrorCode::ConstraintViolation
+                && f.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE
+        }
+        _ => false,
+    };
+}
+
+#[async_trait]
+impl MigrationStore for SqliteDB {
+    fn migrations(&self) -> &'static [Migration] {
+        return migrate::SQLITE;
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<i64>> {
+        return self
+            .run(|conn, _| {
+                // Don't create the table here, so a dry run doesn't change
+                // anything
+                let exists = conn
+                    .query_row(
+                        "SELECT 1 FROM sqlite_master WHERE type = 'table' \
+                            AND name = 'schema_migrations'",
+                        [],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some();
+                if !exists {
+                    return Ok(vec![]);
+                }
+
+                let versions = conn
+                    .prepare("SELECT version FROM schema_migrations ORDER BY version")?
+                    .query_map([], |r| r.get(0))?
+                    .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+                Ok(versions)
+            })
+            .await;
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<bool> {
+        let (version, name, sql) = (migration.version, migration.name, migration.sql);
+
+        return self
+            .run(move |conn, _| {
+                // Take the write lock up front so other processes wait for this
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+                tx.execute_batch(MIGRATIONS_TABLE)?;
+                let done = tx
+                    .query_row(
+                        "SELECT 1 FROM schema_migrations WHERE version = ?1",
+                        [version],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some();
+                if done {
+                    return Ok(false);
+                }
+
+                tx.execute_batch(sql)?;
+                tx.execute(
+                    "INSERT INTO schema_migrations (version, name) VALUES (?1, ?2)",
+                    params![version, name],
+                )?;
+                tx.commit()?;
+
+                Ok(true)
+            })
+            .await;
+    }
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::alib::crypto::hash_recovery_code;
+    use crate::alib::totp::Algorithm;
+
+    async fn _test_setup() -> SqliteDB {
+        let cipher = Cipher::new(&[0u8; 32]).unwrap();
+
+        let conn = SqliteDB::open(":memory:", cipher).unwrap();
+        migrate::run(&conn).await.unwrap();
+
+        return conn;
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let conn = _test_setup().await;
+
+        assert!(migrate::pending(&conn).await.unwrap().is_empty());
+        assert_eq!(
+            conn.applied_migrations().await.unwrap(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert!(!conn.apply_migration(&migrate::SQLITE[0]).await.unwrap());
+        assert_eq!(migrate::run(&conn).await.unwrap(), 0);
+
+        // A new db has everything pending, and nothing is created until it's
+        // migrated
+        let conn = SqliteDB::open(":memory:", Cipher::new(&[0u8; 32]).unwrap()).unwrap();
+        assert_eq!(
+            migrate::pending(&conn).await.unwrap().len(),
+            migrate::SQLITE.len()
+        );
+        assert!(conn.list_api_keys().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_secrets() {
+        let mut conn = _test_setup().await;
+
+        let ident = "test_ident";
+        let secret = "abc123";
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+        let other = conn
+            .add_api_key(
+                "other.example.com",
+                "def67890",
+                None,
+                &[Scope::Create],
+                None,
+            )
+            .await
+            .unwrap();
+
+        conn.create_secret(owner, ident, secret, &TotpParams::default(), OtpType::Totp)
+            .await
+            .unwrap();
+
+        // Test a duplicate secret, and an owner that doesn't exist
+        let res = conn
+            .create_secret(owner, ident, secret, &TotpParams::default(), OtpType::Totp)
+            .await;
+        assert!(res.unwrap_err().downcast_ref::<DuplicateIdent>().is_some());
+        let res = conn
+            .create_secret(
+                -1,
+                "other_ident",
+                secret,
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await;
+        assert!(res.unwrap_err().downcast_ref::<DuplicateIdent>().is_none());
+
+        let sec = conn.get_secret(Some(owner), ident).await.unwrap();
+        let (id, token) = (sec.id, sec.secret);
+        assert_eq!(token, secret);
+
+        // Other owners can't see it, but a lookup for any owner can
+        assert!(conn.get_secret(Some(other), ident).await.is_err());
+        assert_eq!(conn.get_secret(None, ident).await.unwrap().id, id);
+        let err = conn.delete_secret(Some(other), ident).await.unwrap_err();
+        assert!(err.is::<UnknownIdent>());
+        assert!(conn.get_secret(Some(owner), ident).await.is_ok());
+
+        // The stored token should not be the plaintext secret
+        let raw: String = conn
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT token FROM secrets WHERE id = ?1", [id], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_ne!(raw, secret);
+
+        // Rotating to a new master key should leave the secret readable
+        conn.cipher = Cipher::from_keys(2, &[(1, vec![0u8; 32]), (2, vec![1u8; 32])]).unwrap();
+        assert_eq!(conn.count_stale_secrets().await.unwrap(), 1);
+        assert_eq!(conn.rotate_secrets_batch(10).await.unwrap(), 1);
+        assert_eq!(conn.count_stale_secrets().await.unwrap(), 0);
+        assert_eq!(
+            conn.get_secret_by_id(id).await.unwrap(),
+            (ident.to_string(), secret.to_string())
+        );
+
+        // A time step can only be used once
+        assert!(conn.use_step(id, 100).await.unwrap());
+        assert!(!conn.use_step(id, 100).await.unwrap());
+        assert!(!conn.use_step(id, 99).await.unwrap());
+        assert!(conn.use_step(id, 101).await.unwrap());
+
+        // Failures should count up until they are reset
+        assert_eq!(conn.record_verify_failure(id).await.unwrap(), 1);
+        assert_eq!(conn.record_verify_failure(id).await.unwrap(), 2);
+        assert_eq!(conn.get_locked_until(id).await.unwrap(), None);
+        conn.lock_ident(id, 12345).await.unwrap();
+        assert_eq!(conn.get_locked_until(id).await.unwrap(), Some(12345));
+        conn.reset_verify_failures(id).await.unwrap();
+        assert_eq!(conn.get_locked_until(id).await.unwrap(), None);
+        assert_eq!(conn.record_verify_failure(id).await.unwrap(), 1);
+
+        // The same ident can exist for another owner
+        conn.create_secret(
+            other,
+            ident,
+            "def456",
+            &TotpParams::default(),
+            OtpType::Totp,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            conn.get_secret(Some(other), ident).await.unwrap().secret,
+            "def456"
+        );
+        let err = conn.get_secret(None, ident).await.unwrap_err();
+        assert!(err.is::<AmbiguousIdent>());
+        let err = conn.delete_secret(None, ident).await.unwrap_err();
+        assert!(err.is::<AmbiguousIdent>());
+
+        conn.delete_secret(Some(owner), ident).await.unwrap();
+        assert!(conn.get_secret(Some(owner), ident).await.is_err());
+        assert!(conn.get_secret(Some(other), ident).await.is_ok());
+
+        // A token copied into another owner's row won't open there.  Tokens
+        // are unique, so the original row has to go first.
+        for (id, secret) in [(owner, "ghi789"), (other, "jkl012")] {
+            conn.create_secret(
+                id,
+                "copied_ident",
+                secret,
+                &TotpParams::default(),
+                OtpType::Totp,
+            )
+            .await
+            .unwrap();
+        }
+        {
+            let raw = conn.conn.lock().unwrap();
+            let (token, dek): (String, String) = raw
+                .query_row(
+                    "DELETE FROM secrets WHERE owner_id = ?1 AND ident = ?2 \
+                    RETURNING token, dek",
+                    params![owner, "copied_ident"],
+                    |r| Ok((r.get(0)?, r.get(1)?)),
+                )
+                .unwrap();
+            raw.execute(
+                "UPDATE secrets SET token = ?1, dek = ?2 WHERE owner_id = ?3 AND ident = ?4",
+                params![token, dek, other, "copied_ident"],
+            )
+            .unwrap();
+        }
+        assert!(conn.get_secret(Some(other), "copied_ident").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_assign_unowned() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+
+        // An unowned secret sealed before it was assigned, and a plaintext one
+        let sealed = conn.cipher.seal(None, "sealed_ident", "abc123").unwrap();
+        {
+            let raw = conn.conn.lock().unwrap();
+            raw.execute(
+                "INSERT INTO secrets (ident, token, dek, key_version) VALUES (?1, ?2, ?3, ?4)",
+                params!["sealed_ident", sealed.token, sealed.dek, sealed.key_version],
+            )
+            .unwrap();
+            raw.execute(
+                "INSERT INTO secrets (ident, token) VALUES (?1, ?2)",
+                params!["plain_ident", "def456"],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(conn.assign_unowned_secrets(owner).await.unwrap(), 2);
+        let sec = conn.get_secret(Some(owner), "sealed_ident").await.unwrap();
+        assert_eq!(sec.secret, "abc123");
+        let sec = conn.get_secret(Some(owner), "plain_ident").await.unwrap();
+        assert_eq!(sec.secret, "def456");
+    }
+
+    #[tokio::test]
+    async fn test_totp_params() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+        let params = TotpParams {
+            digits: 8,
+            period: 60,
+            algorithm: Algorithm::Sha512,
+        };
+
+        conn.create_secret(owner, "test_ident", "abc123", &params, OtpType::Totp)
+            .await
+            .unwrap();
+        let sec = conn.get_secret(Some(owner), "test_ident").await.unwrap();
+        assert_eq!(sec.params, params);
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+
+        conn.create_secret(
+            owner,
+            "test_ident",
+            "abc123",
+            &TotpParams::default(),
+            OtpType::Totp,
+        )
+        .await
+        .unwrap();
+        let id = conn.get_secret(Some(owner), "test_ident").await.unwrap().id;
+
+        let hashed = [hash_recovery_code("AAAAA"), hash_recovery_code("BBBBB")];
+        conn.set_recovery_codes(id, &hashed).await.unwrap();
+        let codes = conn.get_recovery_codes(id).await.unwrap();
+        assert_eq!(codes.len(), 2);
+        assert_eq!(codes[0].hashed.hash, hashed[0].hash);
+
+        assert!(conn.use_recovery_code(codes[0].id).await.unwrap());
+        assert!(!conn.use_recovery_code(codes[0].id).await.unwrap());
+        assert_eq!(conn.get_recovery_codes(id).await.unwrap().len(), 1);
+
+        // New codes replace all the old ones
+        conn.set_recovery_codes(id, &hashed[..1]).await.unwrap();
+        assert!(!conn.use_recovery_code(codes[1].id).await.unwrap());
+        let codes = conn.get_recovery_codes(id).await.unwrap();
+        assert_eq!(codes.len(), 1);
+
+        // And they go with the secret
+        conn.delete_secret(Some(owner), "test_ident").await.unwrap();
+        assert!(!conn.use_recovery_code(codes[0].id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_hotp_counter() {
+        let conn = _test_setup().await;
+        let owner = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Create], None)
+            .await
+            .unwrap();
+
+        conn.create_secret(
+            owner,
+            "hotp_ident",
+            "abc123",
+            &TotpParams::default(),
+            OtpType::Hotp,
+        )
+        .await
+        .unwrap();
+
+        let sec = conn.get_secret(Some(owner), "hotp_ident").await.unwrap();
+        assert_eq!(sec.kind, OtpType::Hotp);
+        assert_eq!(sec.counter, 0);
+
+        // Skipping ahead is fine, but never going back
+        assert!(conn.use_counter(sec.id, 3).await.unwrap());
+        assert!(!conn.use_counter(sec.id, 3).await.unwrap());
+        assert!(!conn.use_counter(sec.id, 2).await.unwrap());
+        assert!(conn.use_counter(sec.id, 4).await.unwrap());
+        let sec = conn.get_secret(Some(owner), "hotp_ident").await.unwrap();
+        assert_eq!(sec.counter, 5);
+    }
+
+    #[tokio::test]
+    async fn test_api_key() {
+        let conn = _test_setup().await;
+        let now = chrono::Utc::now().timestamp();
+        let limit = RateLimit {
+            rate: 2.5,
+            burst: 5,
+        };
+
+        let id = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Verify], None)
+            .await
+            .unwrap();
+        let expired = conn
+            .add_api_key(
+                "old.example.com",
+                "def67890",
+                Some(&limit),
+                &[],
+                Some(now - 1),
+            )
+            .await
+            .unwrap();
+
+        assert!(conn.api_key_exists("abc12345").await);
+        assert!(!conn.api_key_exists("abc12346").await);
+
+        let key = conn.get_api_key("abc12345").await.unwrap();
+        assert_eq!(key.host, "test.example.com");
+        assert_eq!(key.scopes, vec![Scope::Verify]);
+        assert!(key.is_active(now));
+        assert!(key.created_at >= now);
+        assert!(key.rate_limit.is_none());
+
+        let key = conn.get_api_key("def67890").await.unwrap();
+        assert_eq!(key.rate_limit, Some(limit));
+        assert!(!key.is_active(now));
+
+        conn.touch_api_key(id).await.unwrap();
+        assert!(conn
+            .get_api_key_by_id(id)
+            .await
+            .unwrap()
+            .last_used_at
+            .is_some());
+
+        let keys = conn.list_api_keys().await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].id, expired);
+
+        // Rotating replaces the key, but keeps the row
+        assert!(conn.rotate_api_key(id, "ghi12345").await.unwrap());
+        assert!(conn.get_api_key("abc12345").await.is_err());
+        assert_eq!(conn.get_api_key("ghi12345").await.unwrap().id, id);
+
+        assert!(conn.set_api_key_expiry(expired, None).await.unwrap());
+        assert!(conn.get_api_key("def67890").await.unwrap().is_active(now));
+        assert!(conn.set_api_key_expiry(expired, Some(now)).await.unwrap());
+        assert!(!conn.get_api_key("def67890").await.unwrap().is_active(now));
+        assert!(!conn.set_api_key_expiry(-1, None).await.unwrap());
+
+        assert!(conn.revoke_api_key(id).await.unwrap());
+        assert!(!conn.get_api_key("ghi12345").await.unwrap().is_active(now));
+        assert!(!conn.rotate_api_key(id, "jkl12345").await.unwrap());
+        assert!(!conn.revoke_api_key(-1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_cert() {
+        let conn = _test_setup().await;
+        let names = vec!["svc.example.com".to_string(), "svc".to_string()];
+
+        let id = conn
+            .add_api_key("test.example.com", "abc12345", None, &[Scope::Verify], None)
+            .await
+            .unwrap();
+        let other = conn
+            .add_api_key("other.example.com", "def67890", None, &[], None)
+            .await
+            .unwrap();
+
+        assert!(conn.get_api_key_by_cert(&names).await.is_err());
+        assert!(conn.set_api_key_cert(id, Some("svc")).await.unwrap());
+        assert!(conn.set_api_key_cert(other, Some("svc")).await.is_err());
+
+        let key = conn.get_api_key_by_cert(&names).await.unwrap();
+        assert_eq!(key.id, id);
+        assert_eq!(key.cert_name.as_deref(), Some("svc"));
+
+        assert!(conn.set_api_key_cert(id, None).await.unwrap());
+        assert!(conn.get_api_key_by_cert(&names).await.is_err());
+        assert!(!conn.set_api_key_cert(-1, None).await.unwrap());
+    }
+}
@@ -0,0 +1,320 @@
+use super::crypto::HashedCode;
+use super::migrate::Migration;
+use super::ratelimit::RateLimit;
+use super::scope::Scope;
+use super::totp::{OtpType, TotpParams};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::error::Error;
+use std::fmt;
+
+/// An API key from the `loc_auth` table, minus the hash
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub host: String,
+    /// The plaintext prefix of the key, which is safe to display
+    pub key_id: String,
+    /// The rate limit for this key, if it overrides the global default
+    pub rate_limit: Option<RateLimit>,
+    pub scopes: Vec<Scope>,
+    /// The name from a client certificate, a SAN or the subject CN, that
+    /// authenticates as this key
+    pub cert_name: Option<String>,
+    /// These are all unix timestamps
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub revoked: bool,
+}
+
+/// A TOTP or HOTP secret from the `secrets` table
+#[derive(Debug, Clone)]
+pub struct Secret {
+    pub id: i64,
+    /// The plaintext base32 secret
+    pub secret: String,
+    pub params: TotpParams,
+    pub kind: OtpType,
+    /// The next counter expected from an HOTP token.  This is always 0 for
+    /// TOTP secrets.
+    pub counter: i64,
+}
+
+/// An unused recovery code from the `recovery_codes` table
+#[derive(Debug, Clone)]
+pub struct RecoveryCode {
+    pub id: i64,
+    pub hashed: HashedCode,
+}
+
+impl ApiKey {
+    /// Check that the key hasn't been revoked and hasn't expired as of the
+    /// given unix timestamp
+    pub fn is_active(&self, now: i64) -> bool {
+        return !self.revoked && self.expires_at.is_none_or(|e| e > now);
+    }
+
+    /// Check whether the key is allowed to use the given scope.  Admin keys
+    /// are allowed everything.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        return self.scopes.contains(&scope) || self.is_admin();
+    }
+
+    /// Admin keys can see and manage the idents of every other key
+    pub fn is_admin(&self) -> bool {
+        return self.scopes.contains(&Scope::Admin);
+    }
+}
+
+/// The error returned by `SecretStore::create_secret()` when the owner
+/// already has a secret for the ident
+#[derive(Debug)]
+pub struct DuplicateIdent;
+
+impl Error for DuplicateIdent {}
+impl fmt::Display for DuplicateIdent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "duplicate entry");
+    }
+}
+
+/// The error returned by `SecretStore::get_secret()` when there is no secret
+/// for the ident
+#[derive(Debug)]
+pub struct UnknownIdent;
+
+impl Error for UnknownIdent {}
+impl fmt::Display for UnknownIdent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "unknown ident");
+    }
+}
+
+/// The error returned by `ApiKeyStore::get_api_key_by_id()` when there is no
+/// api key with the id
+#[derive(Debug)]
+pub struct UnknownApiKey;
+
+impl Error for UnknownApiKey {}
+impl fmt::Display for UnknownApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "unknown api key");
+    }
+}
+
+/// The error returned by `SecretStore::get_secret()` and `delete_secret()`
+/// when no owner was given and the ident exists for more than one
+#[derive(Debug)]
+pub struct AmbiguousIdent;
+
+impl Error for AmbiguousIdent {}
+impl fmt::Display for AmbiguousIdent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "ident exists for multiple owners");
+    }
+}
+
+/// The error returned by a store when it can't reach its database, as
+/// opposed to a problem with the request.  The request can be retried later.
+#[derive(Debug)]
+pub struct DbUnavailable(pub String);
+
+impl Error for DbUnavailable {}
+impl fmt::Display for DbUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "Database unavailable: {}", self.0);
+    }
+}
+
+/// Check whether an error from a store is a `DbUnavailable`
+pub fn is_unavailable(e: &anyhow::Error) -> bool {
+    return e.downcast_ref::<DbUnavailable>().is_some();
+}
+
+/// Storage for the api keys.  Implementations must be safe to share between
+/// the request handler tasks.
+#[async_trait]
+pub trait ApiKeyStore {
+    /// Add a new api key, returning its id
+    async fn add_api_key(
+        &self,
+        host: &str,
+        api_key: &str,
+        rate_limit: Option<&RateLimit>,
+        scopes: &[Scope],
+        expires_at: Option<i64>,
+    ) -> Result<i64>;
+
+    /// Look up and authenticate an API key.  Note that this will return
+    /// revoked and expired keys, so check `ApiKey::is_active()`.
+    async fn get_api_key(&self, api_key: &str) -> Result<ApiKey>;
+
+    /// Get an api key by its id.  This fails with `UnknownApiKey` if there
+    /// isn't one.
+    async fn get_api_key_by_id(&self, id: i64) -> Result<ApiKey>;
+
+    /// Find the api key for a client certificate, given the names from the
+    /// certificate in order of preference.  Like `get_api_key()`, this will
+    /// return revoked and expired keys.
+    async fn get_api_key_by_cert(&self, names: &[String]) -> Result<ApiKey>;
+
+    /// Set or clear the client certificate name for an api key.  A name can
+    /// only belong to one key.  Returns false if there is no such key.
+    async fn set_api_key_cert(&self, id: i64, name: Option<&str>) -> Result<bool>;
+
+    /// Set or clear the time, as a unix timestamp, after which an api key
+    /// stops working.  Returns false if there is no such key.
+    async fn set_api_key_expiry(&self, id: i64, expires_at: Option<i64>) -> Result<bool>;
+
+    /// List all the api keys, including revoked and expired ones, by id
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>>;
+
+    /// Revoke an api key so it can no longer be used.  Returns false if
+    /// there is no such key.
+    async fn revoke_api_key(&self, id: i64) -> Result<bool>;
+
+    /// Replace the key for an existing api key, keeping its id, scopes and
+    /// the idents it owns.  The old key stops working immediately.  Returns
+    /// false if there is no such key or it has been revoked.
+    async fn rotate_api_key(&self, id: i64, api_key: &str) -> Result<bool>;
+
+    /// Record that an api key was just used
+    async fn touch_api_key(&self, id: i64) -> Result<()>;
+
+    #[allow(dead_code)]
+    async fn api_key_exists(&self, api_key: &str) -> bool {
+        return self.get_api_key(api_key).await.is_ok();
+    }
+
+    /// Hash any API keys that are still stored in plaintext from before
+    /// hashing was added, returning the number of keys converted.  Only the
+    /// Postgres store can have any.
+    async fn hash_plaintext_api_keys(&self) -> Result<u64> {
+        return Ok(0);
+    }
+}
+
+/// Storage for the TOTP secrets.
+///
+/// Idents are namespaced by the api key that owns them.  Where an owner is
+/// optional, `None` means any owner, which is only for admin keys.  If the
+/// same ident exists for multiple owners, it's an error to look it up
+/// without an owner.
+#[async_trait]
+pub trait SecretStore {
+    /// Store a new secret.  This fails with `DuplicateIdent` if the owner
+    /// already has the ident.  HOTP secrets start at counter 0.
+    async fn create_secret(
+        &self,
+        owner: i64,
+        ident: &str,
+        secret: &str,
+        params: &TotpParams,
+        kind: OtpType,
+    ) -> Result<()>;
+
+    /// Delete the secret for an ident.  This fails with `UnknownIdent` if
+    /// the owner doesn't have the ident, or `AmbiguousIdent` if no owner was
+    /// given and more than one has it.
+    async fn delete_secret(&self, owner: Option<i64>, ident: &str) -> Result<()>;
+
+    /// Get the secret for an ident.  This fails with `UnknownIdent` if the
+    /// owner doesn't have the ident, or `AmbiguousIdent` if no owner was
+    /// given and more than one has it.
+    async fn get_secret(&self, owner: Option<i64>, ident: &str) -> Result<Secret>;
+
+    /// Get the ident and plaintext secret for a secret's id
+    #[allow(dead_code)]
+    async fn get_secret_by_id(&self, id: i64) -> Result<(String, String)>;
+
+    /// Assign every secret without an owner, those created before idents
+    /// were scoped to api keys, to the given key.  Encrypted secrets are
+    /// sealed again for the new owner.  Returns the number of secrets
+    /// updated.
+    async fn assign_unowned_secrets(&self, owner: i64) -> Result<u64>;
+
+    /*
+     * The following methods all work on the secret's id, as returned by
+     * get_secret(), so the owner has already been checked
+     */
+
+    /// Record that a code for the given time step was accepted for the secret.
+    /// This only succeeds if the step is later than the last one used, so a
+    /// code can never be accepted twice.  Returns false for a replayed code.
+    /// This must be atomic.
+    async fn use_step(&self, id: i64, step: i64) -> Result<bool>;
+
+    /// Record that an HOTP code for the given counter was accepted, moving
+    /// the secret's counter on to the one after it.  This only succeeds if
+    /// the counter isn't behind the secret's, so a code can never be accepted
+    /// twice.  Returns false for a replayed code.  This must be atomic.
+    async fn use_counter(&self, id: i64, counter: i64) -> Result<bool>;
+
+    /// Replace all of the secret's recovery codes, used or not, with the new
+    /// ones.  This must be atomic.
+    async fn set_recovery_codes(&self, id: i64, codes: &[HashedCode]) -> Result<()>;
+
+    /// Get the secret's recovery codes that haven't been used yet
+    async fn get_recovery_codes(&self, id: i64) -> Result<Vec<RecoveryCode>>;
+
+    /// Mark a recovery code, by its own id, as used.  This only succeeds if
+    /// it hasn't been used already, so a code can never be accepted twice.
+    /// Returns false for a used code.  This must be atomic.
+    async fn use_recovery_code(&self, code_id: i64) -> Result<bool>;
+
+    /// Return the time, as a unix timestamp, that the secret is locked until
+    /// due to failed verifications.  This may be in the past.
+    async fn get_locked_until(&self, id: i64) -> Result<Option<i64>>;
+
+    /// Increment the consecutive failed verification count for the secret,
+    /// returning the new count
+    async fn record_verify_failure(&self, id: i64) -> Result<i32>;
+
+    /// Lock the secret from verifications until the given unix timestamp
+    async fn lock_ident(&self, id: i64, until: i64) -> Result<()>;
+
+    /// Clear the failure count and any lock after a successful verification
+    async fn reset_verify_failures(&self, id: i64) -> Result<()>;
+
+    /// Return the number of secrets that are not yet sealed with the active
+    /// master key.  Stores that don't encrypt secrets always return 0.
+    async fn count_stale_secrets(&self) -> Result<i64>;
+
+    /// Rewrap the data keys for up to `batch_size` secrets that are sealed
+    /// with an older master key, returning the number of secrets updated
+    async fn rotate_secrets_batch(&self, batch_size: i64) -> Result<u64>;
+
+    /// Encrypt any secrets that are still stored in plaintext from before
+    /// encryption was added, returning the number of secrets converted.  Only
+    /// the Postgres store can have any.
+    async fn encrypt_plaintext_secrets(&self) -> Result<u64> {
+        return Ok(0);
+    }
+}
+
+/// The schema migrations for a store.  Stores without a schema, like the
+/// in-memory one, can use the defaults, which have nothing to migrate.
+#[async_trait]
+pub trait MigrationStore {
+    /// All the migrations for the store, in order
+    fn migrations(&self) -> &'static [Migration] {
+        return &[];
+    }
+
+    /// Return the versions of the migrations that have been applied
+    async fn applied_migrations(&self) -> Result<Vec<i64>> {
+        return Ok(vec![]);
+    }
+
+    /// Apply a migration and record it, in one transaction.  Returns false
+    /// if it had already been applied, by another server for instance.
+    async fn apply_migration(&self, _migration: &Migration) -> Result<bool> {
+        return Ok(false);
+    }
+}
+
+/// Everything the server needs from its storage
+pub trait Store: ApiKeyStore + SecretStore + MigrationStore + Send + Sync {}
+
+impl<T: ApiKeyStore + SecretStore + MigrationStore + Send + Sync> Store for T {}
@@ -0,0 +1,435 @@
+use super::error::recover_lock;
+use anyhow::{anyhow, Context, Result};
+use configparser::ini::Ini;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// The longest a client gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The paths from the `[tls]` config section
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    /// The PEM certificate chain, leaf first
+    pub cert: String,
+    /// The PEM private key for the certificate
+    pub key: String,
+    /// The PEM CA certificates to verify client certificates against
+    pub client_ca: Option<String>,
+    /// Whether every client must present a certificate when `client_ca` is
+    /// set, rather than only verifying the ones that do
+    pub require_client_cert: bool,
+}
+
+impl TlsSettings {
+    /// Read the settings from the config, returning `None` if TLS isn't
+    /// configured
+    pub fn from_config(conf: &Ini) -> Result<Option<Self>> {
+        let cert = conf.get("tls", "cert").filter(|c| !c.is_empty());
+        let key = conf.get("tls", "key").filter(|k| !k.is_empty());
+
+        let (cert, key) = match (cert, key) {
+            (Some(c), Some(k)) => (c, k),
+            (None, None) => return Ok(None),
+            _ => return Err(anyhow!("[tls] cert and key must be set together")),
+        };
+
+        return Ok(Some(Self {
+            cert,
+            key,
+            client_ca: conf.get("tls", "client_ca").filter(|c| !c.is_empty()),
+            require_client_cert: conf
+                .getbool("tls", "require_client_cert")
+                .map_err(|e| anyhow!(e))?
+                .unwrap_or(true),
+        }));
+    }
+
+    /// Load the certificates and key from disk and build the rustls config
+    pub fn load(&self) -> Result<ServerConfig> {
+        let certs = read_certs(&self.cert)?;
+        if certs.is_empty() {
+            return Err(anyhow!("No certificates found in {}", self.cert));
+        }
+        let key = read_key(&self.key)?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots
+                        .add(&cert)
+                        .with_context(|| format!("Invalid CA certificate in {}", path))?;
+                }
+                let verifier = if self.require_client_cert {
+                    AllowAnyAuthenticatedClient::new(roots).boxed()
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("Invalid TLS certificate or key")?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        return Ok(config);
+    }
+}
+
+fn open_pem(path: &str) -> Result<BufReader<File>> {
+    let f = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+
+    return Ok(BufReader::new(f));
+}
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?)
+        .with_context(|| format!("Failed to read certificates from {}", path))?;
+
+    return Ok(certs.into_iter().map(Certificate).collect());
+}
+
+/// Read the first private key in the file, in any of the supported formats
+fn read_key(path: &str) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let items = rustls_pemfile::read_all(&mut open_pem(path)?)
+        .with_context(|| format!("Failed to read the private key from {}", path))?;
+
+    for item in items {
+        match item {
+            Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => return Ok(PrivateKey(k)),
+            _ => (),
+        }
+    }
+
+    return Err(anyhow!("No private key found in {}", path));
+}
+
+/// Get the names from a DER client certificate that it can be mapped to an
+/// api key by, in order of preference: the DNS, URI and email SANs.  The
+/// subject CN is only used when the certificate has none of those.
+pub fn cert_names(der: &[u8]) -> Result<Vec<String>> {
+    let (_, cert) =
+        X509Certificate::from_der(der).map_err(|e| anyhow!("Invalid certificate: {}", e))?;
+    let (mut dns, mut uris, mut emails) = (vec![], vec![], vec![]);
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(n) => dns.push(n.to_string()),
+                GeneralName::URI(n) => uris.push(n.to_string()),
+                GeneralName::RFC822Name(n) => emails.push(n.to_string()),
+                _ => (),
+            }
+        }
+    }
+
+    let mut names = [dns, uris, emails].concat();
+    if names.is_empty() {
+        for cn in cert.subject().iter_common_name() {
+            if let Ok(cn) = cn.as_str() {
+                names.push(cn.to_string());
+            }
+        }
+    }
+
+    return Ok(names);
+}
+
+/// How api clients authenticate, from `[auth] client_auth`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// With the `api_key` in the request
+    ApiKey,
+    /// With a client certificate mapped to an api key
+    Cert,
+    /// With a mapped client certificate if there is one, or else an `api_key`
+    Either,
+    /// With both a client certificate and the `api_key` for the same key
+    Both,
+}
+
+impl ClientAuth {
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        return match conf.get("auth", "client_auth") {
+            Some(s) => s.parse(),
+            None => Ok(Self::ApiKey),
+        };
+    }
+
+    /// Whether a client certificate is checked at all
+    pub fn uses_cert(&self) -> bool {
+        return *self != Self::ApiKey;
+    }
+}
+
+impl FromStr for ClientAuth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        return match s.trim() {
+            "api_key" => Ok(Self::ApiKey),
+            "cert" => Ok(Self::Cert),
+            "either" => Ok(Self::Either),
+            "both" => Ok(Self::Both),
+            _ => Err(anyhow!("Invalid client_auth: {}", s)),
+        };
+    }
+}
+
+impl fmt::Display for ClientAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::ApiKey => "api_key",
+            Self::Cert => "cert",
+            Self::Either => "either",
+            Self::Both => "both",
+        };
+
+        return write!(f, "{}", s);
+    }
+}
+
+/// The TLS acceptor for the server.  The config can be swapped out while the
+/// server is running, which only affects new connections.
+#[derive(Clone)]
+pub struct TlsServer {
+    settings: TlsSettings,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsServer {
+    pub fn new(settings: TlsSettings) -> Result<Self> {
+        let config = settings.load()?;
+
+        return Ok(Self {
+            settings,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        });
+    }
+
+    /// Reload the certificates and key from disk.  If that fails, the
+    /// current ones are kept.
+    pub fn reload(&self) -> Result<()> {
+        let config = self.settings.load()?;
+        // The config is swapped in a single assignment, so it's never half
+        // updated
+        *recover_lock(self.config.write()) = Arc::new(config);
+
+        return Ok(());
+    }
+
+    /// Reload the certificates whenever the process gets a SIGHUP, so they
+    /// can be rotated without a restart
+    pub fn reload_on_sighup(&self) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = signal(SignalKind::hangup())?;
+        let server = self.clone();
+
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                match server.reload() {
+                    Ok(_) => info!("Reloaded the TLS certificates"),
+                    Err(e) => error!("Failed to reload the TLS certificates: {:#}", e),
+                }
+            }
+        });
+
+        return Ok(());
+    }
+
+    /// Do the handshake with a new client, returning the stream and the
+    /// names from the client's certificate, if it presented one
+    pub async fn accept(
+        &self,
+        sock: TcpStream,
+    ) -> Result<(TlsStream<TcpStream>, Option<Vec<String>>)> {
+        // See reload() for why the lock can be recovered
+        let config = recover_lock(self.config.read()).clone();
+
+        // Don't let a client that never finishes the handshake hold on to
+        // the connection
+        let stream =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, TlsAcceptor::from(config).accept(sock))
+                .await
+                .map_err(|_| anyhow!("The TLS handshake timed out"))??;
+
+        let names = match stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|c| c.first())
+        {
+            Some(cert) => Some(cert_names(&cert.0)?),
+            None => None,
+        };
+
+        return Ok((stream, names));
+    }
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn _conf(s: &str) -> Ini {
+        let mut conf = Ini::new();
+        conf.read(s.to_string()).unwrap();
+
+        return conf;
+    }
+
+    #[test]
+    fn test_settings() {
+        assert!(TlsSettings::from_config(&_conf("[main]\nport = 9005\n"))
+            .unwrap()
+            .is_none());
+        assert!(TlsSettings::from_config(&_conf("[tls]\ncert =\nkey =\n"))
+            .unwrap()
+            .is_none());
+        assert!(TlsSettings::from_config(&_conf("[tls]\ncert = /tmp/cert.pem\n")).is_err());
+
+        let s = TlsSettings::from_config(&_conf(
+            "[tls]\ncert = /tmp/cert.pem\nkey = /tmp/key.pem\nclient_ca = /tmp/ca.pem\n",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(s.cert, "/tmp/cert.pem");
+        assert_eq!(s.key, "/tmp/key.pem");
+        assert_eq!(s.client_ca.as_deref(), Some("/tmp/ca.pem"));
+        assert!(s.require_client_cert);
+    }
+
+    /// The settings for the self-signed fixture in testdata
+    fn _test_settings() -> TlsSettings {
+        return TlsSettings {
+            cert: concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/cert.pem").to_string(),
+            key: concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/key.pem").to_string(),
+            client_ca: None,
+            require_client_cert: true,
+        };
+    }
+
+    fn _current(server: &TlsServer) -> Arc<ServerConfig> {
+        return recover_lock(server.config.read()).clone();
+    }
+
+    #[test]
+    fn test_load() {
+        let config = _test_settings().load().unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+
+        let mut s = _test_settings();
+        s.client_ca = Some(s.cert.clone());
+        assert!(s.load().is_ok());
+
+        // The cert isn't a key
+        let mut s = _test_settings();
+        s.key = s.cert.clone();
+        assert!(s.load().is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        // Work on a copy, so the key can be broken without touching the
+        // fixture
+        let dir = std::env::temp_dir().join(format!("gauth-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut s = _test_settings();
+        for (path, name) in [(&mut s.cert, "cert.pem"), (&mut s.key, "key.pem")] {
+            let copy = dir.join(name);
+            std::fs::copy(&path, &copy).unwrap();
+            *path = copy.to_str().unwrap().to_string();
+        }
+
+        let server = TlsServer::new(s.clone()).unwrap();
+        let first = _current(&server);
+
+        server.reload().unwrap();
+        let second = _current(&server);
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        // A bad key on disk fails the reload and keeps the loaded config
+        std::fs::write(&s.key, "not a key").unwrap();
+        assert!(server.reload().is_err());
+        assert!(Arc::ptr_eq(&second, &_current(&server)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let s = TlsSettings {
+            cert: "/nonexistent/cert.pem".to_string(),
+            key: "/nonexistent/key.pem".to_string(),
+            client_ca: None,
+            require_client_cert: true,
+        };
+
+        assert!(s.load().is_err());
+        assert!(TlsServer::new(s).is_err());
+    }
+
+    #[test]
+    fn test_client_auth() {
+        assert_eq!(
+            ClientAuth::from_config(&_conf("[auth]\n")).unwrap(),
+            ClientAuth::ApiKey
+        );
+        assert_eq!(
+            ClientAuth::from_config(&_conf("[auth]\nclient_auth = either\n")).unwrap(),
+            ClientAuth::Either
+        );
+        assert!(ClientAuth::from_config(&_conf("[auth]\nclient_auth = bogus\n")).is_err());
+
+        for a in [ClientAuth::ApiKey, ClientAuth::Cert, ClientAuth::Both] {
+            assert_eq!(a.to_string().parse::<ClientAuth>().unwrap(), a);
+        }
+        assert!(!ClientAuth::ApiKey.uses_cert());
+        assert!(ClientAuth::Both.uses_cert());
+    }
+
+    fn _der(name: &str) -> Vec<u8> {
+        let path = format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name);
+
+        return read_certs(&path).unwrap().remove(0).0;
+    }
+
+    #[test]
+    fn test_cert_names() {
+        // The fixture lists its SANs as email, URI then DNS
+        assert_eq!(
+            cert_names(&_der("cert.pem")).unwrap(),
+            vec![
+                "client.example.com",
+                "spiffe://example.com/client",
+                "client@example.com",
+            ]
+        );
+        assert_eq!(cert_names(&_der("cn_only.pem")).unwrap(), vec!["client-cn"]);
+    }
+
+    #[test]
+    fn test_cert_names_invalid() {
+        assert!(cert_names(b"not a certificate").is_err());
+    }
+}
@@ -0,0 +1,707 @@
+use anyhow::{anyhow, Result};
+use configparser::ini::Ini;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+/// The RFC 4648 base32 alphabet that TOTP secrets are encoded with
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// The number of digits a code can have.  RFC 4226 requires at least 6, and
+/// 8 is the most that authenticator apps support.
+const MIN_DIGITS: u32 = 6;
+const MAX_DIGITS: u32 = 8;
+
+/// The HMAC hash used to generate the codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn hmac(&self, key: &[u8], msg: &[u8]) -> Vec<u8> {
+        return match self {
+            Self::Sha1 => mac::<Hmac<Sha1>>(key, msg),
+            Self::Sha256 => mac::<Hmac<Sha256>>(key, msg),
+            Self::Sha512 => mac::<Hmac<Sha512>>(key, msg),
+        };
+    }
+}
+
+fn mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC takes a key of any size");
+    mac.update(msg);
+
+    return mac.finalize().into_bytes().to_vec();
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        return match s.trim().to_ascii_uppercase().replace('-', "").as_str() {
+            "SHA1" => Ok(Self::Sha1),
+            "SHA256" => Ok(Self::Sha256),
+            "SHA512" => Ok(Self::Sha512),
+            _ => Err(anyhow!("Invalid algorithm: {}", s)),
+        };
+    }
+}
+
+/// This is the name used in otpauth URLs and stored in the db
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA256",
+            Self::Sha512 => "SHA512",
+        };
+
+        return write!(f, "{}", s);
+    }
+}
+
+/// Whether a secret's codes are time based (RFC 6238) or counter based
+/// (RFC 4226), like the codes from event based hardware tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OtpType {
+    #[default]
+    Totp,
+    Hotp,
+}
+
+impl FromStr for OtpType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        return match s.trim().to_ascii_lowercase().as_str() {
+            "totp" => Ok(Self::Totp),
+            "hotp" => Ok(Self::Hotp),
+            _ => Err(anyhow!("Invalid type: {}", s)),
+        };
+    }
+}
+
+/// This is the name used in otpauth URLs and stored in the db
+impl fmt::Display for OtpType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Totp => "totp",
+            Self::Hotp => "hotp",
+        };
+
+        return write!(f, "{}", s);
+    }
+}
+
+/// How the codes for a secret are generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TotpParams {
+    /// The number of digits in a code
+    pub digits: u32,
+    /// The length of a time step in seconds
+    pub period: u64,
+    pub algorithm: Algorithm,
+}
+
+/// The parameters every authenticator app supports, which are all that older
+/// secrets were created with
+impl Default for TotpParams {
+    fn default() -> Self {
+        return Self {
+            digits: 6,
+            period: 30,
+            algorithm: Algorithm::Sha1,
+        };
+    }
+}
+
+/// The parameters that clients can choose from for new secrets, from the
+/// `[totp]` config section
+#[derive(Debug, Clone)]
+pub struct TotpLimits {
+    /// The parameters used for anything a client doesn't ask for
+    pub defaults: TotpParams,
+    pub min_digits: u32,
+    pub max_digits: u32,
+    pub min_period: u64,
+    pub max_period: u64,
+    pub algorithms: Vec<Algorithm>,
+}
+
+impl TotpLimits {
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        let uint = |key: &str, default: u64| -> Result<u64> {
+            return Ok(conf
+                .getuint("totp", key)
+                .map_err(|e| anyhow!(e))?
+                .unwrap_or(default));
+        };
+        let base = TotpParams::default();
+
+        let defaults = TotpParams {
+            digits: uint("digits", base.digits as u64)? as u32,
+            period: uint("period", base.period)?,
+            algorithm: match conf.get("totp", "algorithm") {
+                Some(a) => a.parse()?,
+                None => base.algorithm,
+            },
+        };
+        let algorithms = match conf.get("totp", "algorithms") {
+            Some(list) => list
+                .split(',')
+                .filter(|a| !a.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<Algorithm>>>()?,
+            None => vec![defaults.algorithm],
+        };
+
+        let ret = Self {
+            min_digits: uint("min_digits", defaults.digits as u64)? as u32,
+            max_digits: uint("max_digits", defaults.digits as u64)? as u32,
+            min_period: uint("min_period", defaults.period)?,
+            max_period: uint("max_period", defaults.period)?,
+            algorithms,
+            defaults,
+        };
+
+        if ret.min_digits < MIN_DIGITS || ret.max_digits > MAX_DIGITS {
+            return Err(anyhow!(
+                "[totp] digits must be between {} and {}",
+                MIN_DIGITS,
+                MAX_DIGITS
+            ));
+        } else if ret.min_period == 0 {
+            return Err(anyhow!("[totp] period must be at least 1"));
+        }
+        // Make sure the defaults are allowed themselves
+        ret.check(&ret.defaults)
+            .map_err(|e| anyhow!("Invalid [totp] defaults: {}", e))?;
+
+        return Ok(ret);
+    }
+
+    /// Build the parameters for a new secret from what the client asked for,
+    /// using the defaults for anything it didn't
+    pub fn params(
+        &self,
+        digits: Option<u32>,
+        period: Option<u64>,
+        algorithm: Option<&str>,
+    ) -> Result<TotpParams> {
+        let params = TotpParams {
+            digits: digits.unwrap_or(self.defaults.digits),
+            period: period.unwrap_or(self.defaults.period),
+            algorithm: match algorithm {
+                Some(a) => a.parse()?,
+                None => self.defaults.algorithm,
+            },
+        };
+        self.check(&params)?;
+
+        return Ok(params);
+    }
+
+    fn check(&self, params: &TotpParams) -> Result<()> {
+        if params.digits < self.min_digits || params.digits > self.max_digits {
+            return Err(anyhow!(
+                "digits must be between {} and {}",
+                self.min_digits,
+                self.max_digits
+            ));
+        } else if params.period < self.min_period || params.period > self.max_period {
+            return Err(anyhow!(
+                "period must be between {} and {}",
+                self.min_period,
+                self.max_period
+            ));
+        } else if !self.algorithms.contains(&params.algorithm) {
+            return Err(anyhow!("The {} algorithm isn't allowed", params.algorithm));
+        }
+
+        return Ok(());
+    }
+}
+
+/// The clock drift window for `/verify`, in time steps on either side of
+/// the current one, from `[auth] verify_window` and `max_verify_window`
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyWindow {
+    /// The window used when a request doesn't ask for one
+    pub default: u64,
+    /// The largest window a request can ask for
+    pub max: u64,
+}
+
+impl VerifyWindow {
+    pub fn from_config(conf: &Ini) -> Result<Self> {
+        let default = conf
+            .getuint("auth", "verify_window")
+            .map_err(|e| anyhow!(e))?
+            .unwrap_or(0);
+        let max = conf
+            .getuint("auth", "max_verify_window")
+            .map_err(|e| anyhow!(e))?
+            .unwrap_or(default);
+
+        if default > max {
+            return Err(anyhow!(
+                "[auth] verify_window can't be more than max_verify_window"
+            ));
+        }
+
+        return Ok(Self { default, max });
+    }
+
+    /// Get the window to use, from the request if it was passed in or the
+    /// default otherwise, capped at the max
+    pub fn get(&self, requested: Option<u64>) -> u64 {
+        let window = requested.unwrap_or(self.default);
+        if window > self.max {
+            debug!(
+                "Requested window {} is over the max of {}",
+                window, self.max
+            );
+            return self.max;
+        }
+
+        return window;
+    }
+}
+
+/// Generate a new random secret of `len` base32 characters
+pub fn gen_secret(len: usize) -> String {
+    use rand::prelude::*;
+
+    let mut rng = thread_rng();
+    return (0..len)
+        .map(|_| BASE32_ALPHABET[rng.gen_range(0..32)] as char)
+        .collect();
+}
+
+/// Decode a base32 secret.  This is case insensitive and ignores padding and
+/// whitespace, which some clients add for readability.
+pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut ret = vec![];
+    let mut buf: u32 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+        let c = c.to_ascii_uppercase();
+        let val = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+
+        buf = (buf << 5) | val;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+
+    return Some(ret);
+}
+
+/// Generate the RFC 4226 HOTP code for the counter
+pub fn hotp(key: &[u8], counter: u64, digits: u32, algorithm: Algorithm) -> String {
+    let hash = algorithm.hmac(key, &counter.to_be_bytes());
+
+    // The dynamic truncation from RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    return format!(
+        "{:0width$}",
+        bin % 10u32.pow(digits),
+        width = digits as usize
+    );
+}
+
+/// Return the current TOTP time step for the period
+pub fn current_step(period: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the epoch")
+        .as_secs();
+
+    return now / period;
+}
+
+/// Find the time step that the code is valid for, checking up to `window`
+/// steps on either side of the current step.  This returns the matched step
+/// and its offset from the current step, or `None` if the code doesn't match
+/// any of them.
+pub fn matching_step(
+    secret: &str,
+    params: &TotpParams,
+    code: &str,
+    window: u64,
+) -> Option<(u64, i64)> {
+    return matching_step_at(secret, params, code, current_step(params.period), window);
+}
+
+/// Same as `matching_step()`, but relative to the given step rather than now.
+/// The steps closest to `now` are checked first, so the smallest offset wins.
+pub fn matching_step_at(
+    secret: &str,
+    params: &TotpParams,
+    code: &str,
+    now: u64,
+    window: u64,
+) -> Option<(u64, i64)> {
+    let key = match base32_decode(secret) {
+        Some(k) => k,
+        None => {
+            error!("The secret isn't valid base32");
+            return None;
+        }
+    };
+
+    for dist in 0..=window {
+        for step in [now.checked_sub(dist), now.checked_add(dist)] {
+            let step = match step {
+                Some(s) => s,
+                None => continue,
+            };
+
+            if code_eq(&hotp(&key, step, params.digits, params.algorithm), code) {
+                return Some((step, step as i64 - now as i64));
+            }
+
+            // No need to check the same step twice
+            if dist == 0 {
+                break;
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Find the counter that an HOTP code is valid for, checking the expected
+/// counter and up to `look_ahead` counters after it, since a token's counter
+/// moves on every time a code is generated, even if it's never used
+pub fn matching_counter(
+    secret: &str,
+    params: &TotpParams,
+    code: &str,
+    counter: u64,
+    look_ahead: u64,
+) -> Option<u64> {
+    let key = match base32_decode(secret) {
+        Some(k) => k,
+        None => {
+            error!("The secret isn't valid base32");
+            return None;
+        }
+    };
+
+    return (counter..=counter.saturating_add(look_ahead))
+        .find(|c| code_eq(&hotp(&key, *c, params.digits, params.algorithm), code));
+}
+
+/// Find where the counter of an HOTP token that has drifted past the look
+/// ahead window is, from two consecutive codes.  This checks up to `window`
+/// counters after the expected one and returns the counter of the second
+/// code.  A single code isn't enough over a window this big, since a random
+/// guess would be too likely to match.
+pub fn resync_counter(
+    secret: &str,
+    params: &TotpParams,
+    code: &str,
+    next_code: &str,
+    counter: u64,
+    window: u64,
+) -> Option<u64> {
+    let key = base32_decode(secret)?;
+    let code_at = |c: u64| hotp(&key, c, params.digits, params.algorithm);
+
+    // Both codes are always compared, so the time taken doesn't give away
+    // whether the first one matched
+    return (counter..=counter.saturating_add(window))
+        .find(|c| code_eq(&code_at(*c), code) & code_eq(&code_at(c + 1), next_code))
+        .map(|c| c + 1);
+}
+
+/// Compare a generated code with the one a client sent in constant time, so
+/// the time taken doesn't give away how much of the code was right
+fn code_eq(expected: &str, code: &str) -> bool {
+    return expected.as_bytes().ct_eq(code.as_bytes()).into();
+}
+
+/// Build the otpauth URL that authenticator apps read from the QR code.  The
+/// parameters are only included when they differ from the defaults, which
+/// some apps don't understand.  An HOTP secret is given with the `counter`
+/// that the token should start from, while a TOTP secret has none.
+pub fn otpauth_url(
+    secret: &str,
+    params: &TotpParams,
+    counter: Option<u64>,
+    name: &str,
+    title: &str,
+) -> String {
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+    let kind = match counter {
+        Some(_) => OtpType::Hotp,
+        None => OtpType::Totp,
+    };
+    let mut url = format!(
+        "otpauth://{}/{}?secret={}",
+        kind,
+        utf8_percent_encode(name, NON_ALPHANUMERIC),
+        secret
+    );
+    if !title.is_empty() {
+        url.push_str(&format!(
+            "&issuer={}",
+            utf8_percent_encode(title, NON_ALPHANUMERIC)
+        ));
+    }
+
+    let base = TotpParams::default();
+    if params.algorithm != base.algorithm {
+        url.push_str(&format!("&algorithm={}", params.algorithm));
+    }
+    if params.digits != base.digits {
+        url.push_str(&format!("&digits={}", params.digits));
+    }
+    match counter {
+        Some(c) => url.push_str(&format!("&counter={}", c)),
+        None if params.period != base.period => {
+            url.push_str(&format!("&period={}", params.period));
+        }
+        None => (),
+    }
+
+    return url;
+}
+
+/*
+ * Unit tests
+ */
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    // This is the RFC 6238 test secret, "12345678901234567890", in base32
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn _conf(s: &str) -> Ini {
+        let mut conf = Ini::new();
+        conf.read(s.to_string()).unwrap();
+
+        return conf;
+    }
+
+    #[test]
+    fn test_matching_step() {
+        let p = TotpParams::default();
+
+        // From the RFC 6238 test vectors, T = 59 is step 1
+        assert_eq!(matching_step_at(SECRET, &p, "287082", 1, 0), Some((1, 0)));
+        assert_eq!(matching_step_at(SECRET, &p, "287082", 2, 0), None);
+        assert_eq!(matching_step_at(SECRET, &p, "287082", 2, 1), Some((1, -1)));
+        assert_eq!(matching_step_at(SECRET, &p, "287082", 0, 1), Some((1, 1)));
+        assert_eq!(matching_step_at(SECRET, &p, "287082", 4, 2), None);
+        assert_eq!(matching_step_at(SECRET, &p, "287082", 4, 3), Some((1, -3)));
+
+        let p = TotpParams {
+            digits: 8,
+            ..TotpParams::default()
+        };
+        assert_eq!(matching_step_at(SECRET, &p, "94287082", 1, 0), Some((1, 0)));
+    }
+
+    #[test]
+    fn test_bad_code() {
+        let p = TotpParams::default();
+        assert_eq!(matching_step_at(SECRET, &p, "000000", 1, 0), None);
+        assert_eq!(matching_step_at("not base32!", &p, "287082", 1, 0), None);
+    }
+
+    #[test]
+    fn test_code_eq() {
+        assert!(code_eq("123456", "123456"));
+        assert!(!code_eq("123456", "123457"));
+        assert!(!code_eq("123456", "12345"));
+        assert!(!code_eq("123456", ""));
+    }
+
+    #[test]
+    fn test_hotp() {
+        // The RFC 4226 appendix D test values
+        let key = b"12345678901234567890";
+        let codes = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, code) in codes.iter().enumerate() {
+            assert_eq!(hotp(key, counter as u64, 6, Algorithm::Sha1), *code);
+        }
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // The RFC 6238 appendix B keys and 8 digit codes, at 30 second steps
+        let sha1 = b"12345678901234567890".as_slice();
+        let sha256 = b"12345678901234567890123456789012".as_slice();
+        let sha512 = b"1234567890123456789012345678901234567890123456789012345678901234".as_slice();
+        let cases = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (2000000000, "69279037", "90698825", "38618901"),
+        ];
+
+        for (time, c1, c256, c512) in cases {
+            let step = time / 30;
+            assert_eq!(hotp(sha1, step, 8, Algorithm::Sha1), c1);
+            assert_eq!(hotp(sha256, step, 8, Algorithm::Sha256), c256);
+            assert_eq!(hotp(sha512, step, 8, Algorithm::Sha512), c512);
+        }
+    }
+
+    #[test]
+    fn test_base32() {
+        assert_eq!(base32_decode(SECRET).unwrap(), b"12345678901234567890");
+        assert_eq!(
+            base32_decode("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(),
+            b"12345678901234567890"
+        );
+        assert_eq!(base32_decode("MZXW6===").unwrap(), b"foo");
+        assert!(base32_decode("MZXW1").is_none());
+
+        let secret = gen_secret(32);
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_algorithm() {
+        assert_eq!("sha-256".parse::<Algorithm>().unwrap(), Algorithm::Sha256);
+        for a in [Algorithm::Sha1, Algorithm::Sha256, Algorithm::Sha512] {
+            assert_eq!(a.to_string().parse::<Algorithm>().unwrap(), a);
+        }
+        assert!("md5".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        // With nothing set, only the defaults are allowed
+        let l = TotpLimits::from_config(&_conf("[totp]\n")).unwrap();
+        assert_eq!(l.params(None, None, None).unwrap(), TotpParams::default());
+        assert!(l.params(Some(8), None, None).is_err());
+
+        let l = TotpLimits::from_config(&_conf(
+            "[totp]\nmax_digits = 8\nmax_period = 60\nalgorithms = SHA1,SHA256\n",
+        ))
+        .unwrap();
+        let p = l.params(Some(8), Some(60), Some("SHA256")).unwrap();
+        assert_eq!(p.digits, 8);
+        assert_eq!(p.period, 60);
+        assert_eq!(p.algorithm, Algorithm::Sha256);
+        assert!(l.params(Some(9), None, None).is_err());
+        assert!(l.params(None, Some(15), None).is_err());
+        assert!(l.params(None, None, Some("SHA512")).is_err());
+        assert!(l.params(None, None, Some("bogus")).is_err());
+
+        assert!(TotpLimits::from_config(&_conf("[totp]\nmax_digits = 10\n")).is_err());
+        assert!(TotpLimits::from_config(&_conf("[totp]\ndigits = 8\nmax_digits = 6\n")).is_err());
+        assert!(
+            TotpLimits::from_config(&_conf("[totp]\nalgorithm = SHA256\n"))
+                .unwrap()
+                .algorithms
+                == vec![Algorithm::Sha256]
+        );
+    }
+
+    #[test]
+    fn test_verify_window() {
+        let w = VerifyWindow::from_config(&_conf("[auth]\n")).unwrap();
+        assert_eq!(w.get(None), 0);
+        assert_eq!(w.get(Some(1)), 0);
+
+        let w =
+            VerifyWindow::from_config(&_conf("[auth]\nverify_window = 1\nmax_verify_window = 3\n"))
+                .unwrap();
+        assert_eq!(w.get(None), 1);
+        assert_eq!(w.get(Some(2)), 2);
+        assert_eq!(w.get(Some(10)), 3);
+
+        assert!(VerifyWindow::from_config(&_conf(
+            "[auth]\nverify_window = 3\nmax_verify_window = 1\n"
+        ))
+        .is_err());
+        assert!(VerifyWindow::from_config(&_conf("[auth]\nverify_window = -1\n")).is_err());
+    }
+
+    #[test]
+    fn test_otpauth_url() {
+        let p = TotpParams::default();
+        assert_eq!(
+            otpauth_url("ABC", &p, None, "user@example.com", "Example Co"),
+            "otpauth://totp/user%40example%2Ecom?secret=ABC&issuer=Example%20Co"
+        );
+
+        let p = TotpParams {
+            digits: 8,
+            period: 60,
+            algorithm: Algorithm::Sha256,
+        };
+        assert_eq!(
+            otpauth_url("ABC", &p, None, "user", ""),
+            "otpauth://totp/user?secret=ABC&algorithm=SHA256&digits=8&period=60"
+        );
+        assert_eq!(
+            otpauth_url("ABC", &p, Some(5), "user", ""),
+            "otpauth://hotp/user?secret=ABC&algorithm=SHA256&digits=8&counter=5"
+        );
+    }
+
+    #[test]
+    fn test_matching_counter() {
+        let p = TotpParams::default();
+
+        // The RFC 4226 code for counter 3 is 969429
+        assert_eq!(matching_counter(SECRET, &p, "969429", 3, 0), Some(3));
+        assert_eq!(matching_counter(SECRET, &p, "969429", 0, 3), Some(3));
+        assert_eq!(matching_counter(SECRET, &p, "969429", 0, 2), None);
+        // Counters behind the expected one are never accepted
+        assert_eq!(matching_counter(SECRET, &p, "969429", 4, 10), None);
+    }
+
+    #[test]
+    fn test_resync_counter() {
+        let p = TotpParams::default();
+
+        // Counters 7 and 8 are 162583 and 399871
+        assert_eq!(
+            resync_counter(SECRET, &p, "162583", "399871", 0, 10),
+            Some(8)
+        );
+        assert_eq!(resync_counter(SECRET, &p, "162583", "399871", 0, 6), None);
+        // The codes have to be consecutive, and in order
+        assert_eq!(resync_counter(SECRET, &p, "399871", "162583", 0, 10), None);
+        assert_eq!(resync_counter(SECRET, &p, "162583", "520489", 0, 10), None);
+    }
+
+    #[test]
+    fn test_otp_type() {
+        assert_eq!("HOTP".parse::<OtpType>().unwrap(), OtpType::Hotp);
+        for t in [OtpType::Totp, OtpType::Hotp] {
+            assert_eq!(t.to_string().parse::<OtpType>().unwrap(), t);
+        }
+        assert!("motp".parse::<OtpType>().is_err());
+    }
+}
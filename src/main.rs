@@ -1,16 +1,36 @@
+// Explicit returns are the house style
+#![allow(clippy::needless_return)]
+
 extern crate chrono;
 #[macro_use]
 extern crate log;
 
 mod alib;
 
-use alib::{config::get_config, db::DB, handler::get_router_w_routes};
-use anyhow::Result;
-use clap::Parser;
+use alib::{
+    config::get_config,
+    crypto::Cipher,
+    db::{PoolConfig, DB},
+    handler::get_router_w_routes,
+    listen::{Listen, SocketPerms},
+    memstore::MemStore,
+    migrate,
+    ratelimit::RateLimit,
+    scope::{Scope, DEFAULT_SCOPES},
+    server::{serve, shutdown_signal, Listener},
+    store::{ApiKey, ApiKeyStore, Store},
+    tls::{ClientAuth, TlsServer, TlsSettings},
+};
+use anyhow::{anyhow, Result};
+use clap::{ArgGroup, Parser, Subcommand};
 use configparser::ini::Ini;
-use iron::prelude::*;
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "sqlite")]
+use alib::sqlite::SqliteDB;
 
 #[derive(Parser, Debug)]
 #[clap(author="Jay Deiman", version, about="", long_about=None)]
@@ -24,16 +44,122 @@ struct Args {
         help = "The path to the config file"
     )]
     config: PathBuf,
-    #[clap(
-        short = 'a',
-        long = "create-api-key",
-        default_value = "",
-        help = "Supply a hostname to create an api key.  The new key will \
-            be printed to stdout."
-    )]
-    host: String,
     #[clap(short = 'D', long)]
     debug: bool,
+    /// With no command, the server is started
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the api keys
+    #[clap(subcommand)]
+    Apikey(ApiKeyCommand),
+    /// Assign all secrets that have no owner, created before idents were
+    /// scoped to api keys, to the api key with this id
+    AssignUnowned {
+        #[clap(value_name = "KEY_ID")]
+        owner: i64,
+    },
+    /// Encrypt any secrets still stored in plaintext in the database with the
+    /// configured master key
+    EncryptSecrets,
+    /// Hash any api keys still stored in plaintext in the database
+    HashApiKeys,
+    /// Apply any pending schema migrations to the database
+    Migrate {
+        #[clap(
+            long = "dry-run",
+            help = "Only list the pending migrations, without applying them"
+        )]
+        dry_run: bool,
+    },
+    /// Re-encrypt all secrets under the active master key
+    RotateKeys {
+        #[clap(
+            short,
+            long,
+            default_value_t = 100,
+            help = "The number of rows to re-encrypt per transaction"
+        )]
+        batch_size: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ApiKeyCommand {
+    /// Create a new api key for a host.  The new key will be printed to
+    /// stdout.
+    Create {
+        #[clap(help = "The hostname of the client, stored with the key for reference")]
+        host: String,
+        #[clap(
+            long = "rate-limit",
+            help = "The number of requests per second to allow for the key \
+                instead of the global default"
+        )]
+        rate_limit: Option<f64>,
+        #[clap(long = "rate-burst", help = "The burst size for the key's rate limit")]
+        rate_burst: Option<u32>,
+        #[clap(
+            long,
+            default_value = DEFAULT_SCOPES,
+            help = "The comma separated scopes the key is allowed: create, \
+                verify, qr, delete and admin.  An admin key can do everything, \
+                including managing the idents of every other key."
+        )]
+        scopes: String,
+        #[clap(
+            long = "expires-in-days",
+            value_name = "DAYS",
+            help = "Make the key stop working after this many days"
+        )]
+        expires_in_days: Option<u32>,
+    },
+    /// List all the api keys
+    List,
+    /// Show the details of an api key
+    Show {
+        #[clap(value_name = "KEY_ID")]
+        id: i64,
+    },
+    /// Set when an api key stops working, or clear the expiry
+    #[clap(group(ArgGroup::new("when").required(true).args(&["in-days", "now", "never"])))]
+    Expire {
+        #[clap(value_name = "KEY_ID")]
+        id: i64,
+        #[clap(
+            long = "in-days",
+            value_name = "DAYS",
+            help = "Make the key stop working after this many days from now"
+        )]
+        in_days: Option<u32>,
+        #[clap(long, help = "Make the key stop working immediately")]
+        now: bool,
+        #[clap(long, help = "Clear the expiry, so the key never expires")]
+        never: bool,
+    },
+    /// Revoke an api key so it can no longer be used
+    Revoke {
+        #[clap(value_name = "KEY_ID")]
+        id: i64,
+    },
+    /// Let clients authenticate as an api key with a client certificate that
+    /// has this name as a SAN or the subject CN.  Leave out the name to
+    /// clear it.
+    SetCert {
+        #[clap(value_name = "KEY_ID")]
+        id: i64,
+        #[clap(value_name = "NAME")]
+        name: Option<String>,
+    },
+    /// Replace an api key with a new one, keeping its settings and idents.
+    /// The new key will be printed to stdout.
+    Rotate {
+        #[clap(value_name = "KEY_ID")]
+        id: i64,
+    },
 }
 
 static LOGGER: GlobalLogger = GlobalLogger;
@@ -81,6 +207,10 @@ fn setup_logging(args: &Args) {
     log::set_max_level(l);
 }
 
+fn get_cipher(conf: &Ini) -> Cipher {
+    return Cipher::from_config(conf).expect("Failed to load the master key");
+}
+
 fn get_db_params(conf: &Ini) -> String {
     let ret = format!(
         "user={} password={} dbname={} sslmode={} host={} port={}",
@@ -95,39 +225,391 @@ fn get_db_params(conf: &Ini) -> String {
     return ret;
 }
 
-fn create_api_key(db: &mut DB, host: &str) -> Result<String> {
+/// Generate a new random api key
+fn gen_api_key() -> String {
     use rand::prelude::*;
-    let key: String = thread_rng()
+    return thread_rng()
         .sample_iter(&rand::distributions::Alphanumeric)
         .take(32)
         .map(char::from)
         .collect();
-    db.add_api_key(host, &key)?;
+}
+
+/// Format a unix timestamp for display
+fn fmt_ts(ts: Option<i64>) -> String {
+    use chrono::TimeZone;
+    return match ts.and_then(|t| chrono::Utc.timestamp_opt(t, 0).single()) {
+        Some(d) => d.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        None => "never".to_string(),
+    };
+}
+
+/// Describe whether a key can currently be used
+fn key_status(key: &ApiKey) -> &'static str {
+    if key.revoked {
+        return "revoked";
+    } else if !key.is_active(chrono::Utc::now().timestamp()) {
+        return "expired";
+    }
+
+    return "active";
+}
+
+fn print_api_key(key: &ApiKey) {
+    println!("id:        {}", key.id);
+    println!("host:      {}", key.host);
+    println!("key id:    {}", key.key_id);
+    println!("status:    {}", key_status(key));
+    println!("scopes:    {}", Scope::join(&key.scopes));
+    println!("cert name: {}", key.cert_name.as_deref().unwrap_or("none"));
+    match &key.rate_limit {
+        Some(rl) => println!("rate:      {}/s, burst {}", rl.rate, rl.burst),
+        None => println!("rate:      default"),
+    }
+    println!("created:   {}", fmt_ts(Some(key.created_at)));
+    println!("last used: {}", fmt_ts(key.last_used_at));
+    println!("expires:   {}", fmt_ts(key.expires_at));
+}
+
+/// Run one of the `apikey` subcommands
+async fn api_key_cmd(db: &dyn Store, cmd: ApiKeyCommand) -> Result<()> {
+    match cmd {
+        ApiKeyCommand::Create {
+            host,
+            rate_limit,
+            rate_burst,
+            scopes,
+            expires_in_days,
+        } => {
+            debug!("Creating a new API key for host {}", &host);
+            let rate_limit = rate_limit.map(|rate| RateLimit {
+                rate,
+                burst: rate_burst.unwrap_or(rate.ceil().max(1.0) as u32),
+            });
+            let scopes = Scope::parse_list(&scopes)?;
+            let expires_at =
+                expires_in_days.map(|d| chrono::Utc::now().timestamp() + d as i64 * 86400);
+            let key = gen_api_key();
+            let id = db
+                .add_api_key(&host, &key, rate_limit.as_ref(), &scopes, expires_at)
+                .await?;
+            println!("New API key for {} (id {}): {}", &host, id, &key);
+        }
+        ApiKeyCommand::List => {
+            println!(
+                "{:>6}  {:<8}  {:<7}  {:<20}  {:<20}  HOST",
+                "ID", "KEY ID", "STATUS", "LAST USED", "EXPIRES"
+            );
+            for key in db.list_api_keys().await? {
+                println!(
+                    "{:>6}  {:<8}  {:<7}  {:<20}  {:<20}  {}",
+                    key.id,
+                    key.key_id,
+                    key_status(&key),
+                    fmt_ts(key.last_used_at),
+                    fmt_ts(key.expires_at),
+                    key.host,
+                );
+            }
+        }
+        ApiKeyCommand::Show { id } => {
+            print_api_key(&db.get_api_key_by_id(id).await?);
+        }
+        ApiKeyCommand::Expire {
+            id,
+            in_days,
+            now,
+            never,
+        } => {
+            // Clap makes sure exactly one of the options was given
+            let ts = chrono::Utc::now().timestamp();
+            let expires_at = if never {
+                None
+            } else if now {
+                Some(ts)
+            } else {
+                in_days.map(|d| ts + d as i64 * 86400)
+            };
+            if !db.set_api_key_expiry(id, expires_at).await? {
+                return Err(anyhow!("No api key with id {}", id));
+            }
+            match expires_at {
+                Some(_) => println!("Api key {} expires at {}", id, fmt_ts(expires_at)),
+                None => println!("Api key {} no longer expires", id),
+            }
+        }
+        ApiKeyCommand::Revoke { id } => {
+            if !db.revoke_api_key(id).await? {
+                return Err(anyhow!("No api key with id {}", id));
+            }
+            println!("Revoked api key {}", id);
+        }
+        ApiKeyCommand::SetCert { id, name } => {
+            if !db.set_api_key_cert(id, name.as_deref()).await? {
+                return Err(anyhow!("No api key with id {}", id));
+            }
+            match name {
+                Some(n) => println!("Api key {} can now authenticate as {}", id, n),
+                None => println!("Cleared the certificate name for api key {}", id),
+            }
+        }
+        ApiKeyCommand::Rotate { id } => {
+            let key = gen_api_key();
+            if !db.rotate_api_key(id, &key).await? {
+                return Err(anyhow!("No active api key with id {}", id));
+            }
+            println!("New API key for id {}: {}", id, &key);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Rewrap all the secrets under the active master key in batches, reporting
+/// the progress as we go
+async fn rotate_keys(db: &dyn Store, batch_size: i64) -> Result<u64> {
+    let total = db.count_stale_secrets().await?;
+    let mut done = 0;
+
+    println!("{} secret(s) to re-encrypt", total);
+
+    loop {
+        let count = db.rotate_secrets_batch(batch_size).await?;
+        if count == 0 {
+            break;
+        }
+
+        done += count;
+        println!("Re-encrypted {}/{} secret(s)", done, total);
+    }
+
+    return Ok(done);
+}
 
-    return Ok(key);
+/// Apply the pending migrations, or just list them for a dry run
+async fn migrate_cmd(db: &dyn Store, dry_run: bool) -> Result<()> {
+    let pending = migrate::pending(db).await?;
+
+    if pending.is_empty() {
+        println!("The database is up to date");
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("{} pending migration(s):", pending.len());
+        for m in pending {
+            println!("  {}", m.full_name());
+        }
+        return Ok(());
+    }
+
+    let count = migrate::run(db).await?;
+    println!("Applied {} migration(s)", count);
+
+    return Ok(());
 }
 
-fn main() {
+/// Run one of the management commands against the db
+async fn run_command(db: &dyn Store, cmd: Command) {
+    match cmd {
+        Command::Apikey(cmd) => {
+            if let Err(e) = api_key_cmd(db, cmd).await {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Command::AssignUnowned { owner } => {
+            debug!("Assigning unowned secrets to api key {}", owner);
+            let count = match db.assign_unowned_secrets(owner).await {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Failed to assign the unowned secrets: {}", e);
+                    exit(1);
+                }
+            };
+            println!("Assigned {} secret(s) to api key {}", count, owner);
+        }
+        Command::EncryptSecrets => {
+            debug!("Encrypting plaintext secrets");
+            let count = match db.encrypt_plaintext_secrets().await {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Failed to encrypt the secrets: {}", e);
+                    exit(1);
+                }
+            };
+            println!("Encrypted {} plaintext secret(s)", count);
+        }
+        Command::HashApiKeys => {
+            debug!("Hashing plaintext API keys");
+            let count = match db.hash_plaintext_api_keys().await {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Failed to hash the API keys: {}", e);
+                    exit(1);
+                }
+            };
+            println!("Hashed {} plaintext API key(s)", count);
+        }
+        Command::Migrate { dry_run } => {
+            if let Err(e) = migrate_cmd(db, dry_run).await {
+                eprintln!("Failed to migrate the database: {}", e);
+                exit(1);
+            }
+        }
+        Command::RotateKeys { batch_size } => {
+            debug!("Rotating secrets to the active master key");
+            let count = match rotate_keys(db, batch_size).await {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Failed to rotate the secrets: {}", e);
+                    exit(1);
+                }
+            };
+            println!("Finished rotating {} secret(s)", count);
+        }
+    }
+}
+
+/// Create an in-memory store with a single admin key.  The key is printed,
+/// since there is no other way to get one.
+async fn new_mem_store() -> MemStore {
+    let store = MemStore::new();
+    let key = gen_api_key();
+
+    store
+        .add_api_key("localhost", &key, None, &[Scope::Admin], None)
+        .await
+        .unwrap();
+    println!("Admin API key for the memory store: {}", &key);
+
+    return store;
+}
+
+#[tokio::main]
+async fn main() {
     let args = get_args();
     setup_logging(&args);
     let conf = get_config(&args.config);
-    let db_params = get_db_params(&conf);
-    let mut db = DB::new(&db_params);
+    let backend = conf
+        .get("db", "backend")
+        .unwrap_or_else(|| "postgres".to_string());
 
-    if !args.host.is_empty() {
-        debug!("Creating a new API key for host {}", &args.host);
-        let key = create_api_key(&mut db, &args.host).unwrap();
-        println!("New API key for {}: {}", &args.host, &key);
+    let store: Arc<dyn Store> = match backend.as_str() {
+        "postgres" => {
+            let db_params = get_db_params(&conf);
+            let pool = PoolConfig::from_config(&conf);
+            let retry = conf
+                .getuint("db", "connect_retry_secs")
+                .unwrap()
+                .unwrap_or(60);
+            Arc::new(
+                DB::connect(
+                    &db_params,
+                    get_cipher(&conf),
+                    &pool,
+                    Duration::from_secs(retry),
+                )
+                .await
+                .expect("Failed to connect to the db"),
+            )
+        }
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            let path = conf
+                .get("db", "path")
+                .expect("[db] path is required for sqlite");
+            Arc::new(SqliteDB::open(&path, get_cipher(&conf)).unwrap())
+        }
+        #[cfg(not(feature = "sqlite"))]
+        "sqlite" => {
+            eprintln!("This build doesn't support sqlite, rebuild with --features sqlite");
+            exit(1);
+        }
+        "memory" => {
+            if args.command.is_some() {
+                eprintln!("The memory backend doesn't store anything to manage");
+                exit(1);
+            }
+
+            warn!("Using the memory backend, nothing will be saved");
+            Arc::new(new_mem_store().await)
+        }
+        _ => {
+            eprintln!("Unknown db backend: {}", backend);
+            exit(1);
+        }
+    };
+
+    // SQLite databases have always been set up automatically, so they
+    // default to migrating
+    let auto_migrate = conf
+        .getbool("db", "auto_migrate")
+        .unwrap()
+        .unwrap_or(backend == "sqlite");
+    let migrating = matches!(args.command, Some(Command::Migrate { .. }));
+
+    if auto_migrate && !migrating {
+        migrate::run(store.as_ref())
+            .await
+            .expect("Failed to migrate the database");
+    } else if !migrating {
+        let pending = migrate::pending(store.as_ref())
+            .await
+            .expect("Failed to check migrations");
+        if !pending.is_empty() {
+            warn!(
+                "There are {} pending migration(s), run `gauth-server migrate` \
+                    to apply them",
+                pending.len()
+            );
+        }
+    }
+
+    if let Some(cmd) = args.command {
+        run_command(store.as_ref(), cmd).await;
         exit(0);
     }
 
-    let bind_str = format!(
-        "{}:{}",
-        conf.get("main", "bind_ip").unwrap(),
-        conf.getint("main", "port").unwrap().unwrap(),
-    );
+    let listen = Listen::from_config(&conf).expect("Invalid [main] listen");
+    let perms = SocketPerms::from_config(&conf).expect("Invalid [main] socket settings");
+    let tls = TlsSettings::from_config(&conf).expect("Invalid [tls] config");
+    let client_auth = ClientAuth::from_config(&conf).expect("Invalid [auth] client_auth");
+    if client_auth.uses_cert() && tls.as_ref().is_none_or(|t| t.client_ca.is_none()) {
+        eprintln!(
+            "client_auth = {} needs [tls] client_ca to verify the client certificates",
+            client_auth
+        );
+        exit(1);
+    }
+
+    let routes = get_router_w_routes(conf, store).unwrap();
 
-    let routes = get_router_w_routes(conf, db).unwrap();
+    let tls = match (&listen, tls) {
+        (Listen::Unix(_), Some(_)) => {
+            eprintln!("TLS isn't supported on a unix socket");
+            exit(1);
+        }
+        (_, Some(settings)) => {
+            let server = TlsServer::new(settings).expect("Failed to load the TLS config");
+            server
+                .reload_on_sighup()
+                .expect("Failed to set up the SIGHUP handler");
+            Some(server)
+        }
+        (_, None) => None,
+    };
+
+    let listener = Listener::bind(&listen, &perms)
+        .await
+        .expect("Failed to listen");
+    match (&listen, &tls) {
+        (Listen::Tcp(addr), Some(_)) => info!("Listening with TLS on {}", addr),
+        (Listen::Tcp(addr), None) => info!("Listening on {}", addr),
+        (Listen::Unix(path), _) => info!("Listening on {}", path.display()),
+    }
 
-    Iron::new(routes).http(&bind_str).unwrap();
+    serve(listener, tls, routes, shutdown_signal())
+        .await
+        .unwrap();
 }